@@ -5,6 +5,12 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+use crate::error::{FlashError, Result};
+use crate::transport::{ISP_ENDPOINT_IN, ISP_ENDPOINT_OUT};
+
+/// Size of the unit the ISP Erase and DataErase commands count in
+pub const ERASE_UNIT_SIZE: u32 = 1024;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chip {
     pub name: String,
@@ -29,6 +35,7 @@ pub enum ChipFamily {
     CH573,
     CH32V003,
     CH32X035,
+    CH643,
     Unknown,
 }
 
@@ -49,6 +56,101 @@ pub struct ConfigField {
     pub explaination: Vec<(String, String)>,
 }
 
+/// Build an explanation table from (value, meaning) pairs
+fn explain(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+    entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+fn field(name: &str, bit_range: [u8; 2], entries: &[(&str, &str)]) -> ConfigField {
+    ConfigField {
+        name: name.to_string(),
+        bit_range,
+        explaination: explain(entries),
+    }
+}
+
+/// RDPR_USER register holding RDPR, nRDPR, USER and nUSER, with the family's
+/// USER fields following RDPR
+fn rdpr_user_register(user_fields: Vec<ConfigField>) -> ConfigRegister {
+    let mut fields = vec![field("RDPR", [7, 0], &[("0xa5", "Unprotected"), ("_", "Protected")])];
+    fields.extend(user_fields);
+    ConfigRegister {
+        name: "RDPR_USER".to_string(),
+        offset: 0x00,
+        reset: Some(0x00FF5AA5),
+        // Debug access is only blocked by read protection, so lifting RDPR re-enables it
+        enable_debug: Some(0x00FF5AA5),
+        fields,
+        explaination: vec![],
+    }
+}
+
+/// DATA register holding the two user data bytes and their complements
+fn data_register() -> ConfigRegister {
+    ConfigRegister {
+        name: "DATA".to_string(),
+        offset: 0x04,
+        reset: Some(0xFF00FF00),
+        enable_debug: None,
+        fields: vec![field("DATA0", [7, 0], &[]), field("DATA1", [23, 16], &[])],
+        explaination: vec![],
+    }
+}
+
+/// WPR register, where a cleared bit write-protects a group of sectors
+fn wpr_register() -> ConfigRegister {
+    ConfigRegister {
+        name: "WPR".to_string(),
+        offset: 0x08,
+        reset: Some(0xFFFFFFFF),
+        enable_debug: None,
+        fields: vec![],
+        explaination: explain(&[("0xffffffff", "Unprotected"), ("_", "Some sectors are write-protected")]),
+    }
+}
+
+/// USER fields shared by the CH32F103/CH32V103/CH32V20x/CH32V30x/CH32X035/CH643 families
+fn ch32_user_fields() -> Vec<ConfigField> {
+    vec![
+        field("IWDG_SW", [16, 16], &[("1", "Software"), ("0", "Hardware")]),
+        field("STOP_RST", [17, 17], &[("1", "Disable"), ("0", "Enable")]),
+        field("STANDBY_RST", [18, 18], &[("1", "Disable"), ("0", "Enable")]),
+    ]
+}
+
+/// Option byte registers shared by the CH32 families, with their factory values
+fn ch32_config_registers() -> Vec<ConfigRegister> {
+    vec![rdpr_user_register(ch32_user_fields()), data_register(), wpr_register()]
+}
+
+/// CH32V30x option bytes, whose USER byte also splits SRAM between code and data
+fn ch32v30x_config_registers() -> Vec<ConfigRegister> {
+    let mut user_fields = ch32_user_fields();
+    user_fields.push(field("SRAM_CODE_MODE", [23, 22], &[
+        ("0", "CODE-192KB + RAM-128KB"),
+        ("1", "CODE-224KB + RAM-96KB"),
+        ("2", "CODE-256KB + RAM-64KB"),
+        ("3", "CODE-288KB + RAM-32KB"),
+    ]));
+    vec![rdpr_user_register(user_fields), data_register(), wpr_register()]
+}
+
+/// CH32V003 option bytes, with the reset pin and boot area selected in USER
+fn ch32v003_config_registers() -> Vec<ConfigRegister> {
+    let user_fields = vec![
+        field("IWDG_SW", [16, 16], &[("1", "Software"), ("0", "Hardware")]),
+        field("STANDBY_RST", [18, 18], &[("1", "Disable"), ("0", "Enable")]),
+        field("RST_MODE", [20, 19], &[
+            ("0", "PD7 as reset, 128us delay"),
+            ("1", "PD7 as reset, 1ms delay"),
+            ("2", "PD7 as reset, 12ms delay"),
+            ("3", "PD7 as GPIO"),
+        ]),
+        field("START_MODE", [21, 21], &[("1", "Boot from bootloader"), ("0", "Boot from user code")]),
+    ];
+    vec![rdpr_user_register(user_fields), data_register(), wpr_register()]
+}
+
 impl Chip {
     /// Create CH32V307 chip definition
     pub fn ch32v307() -> Self {
@@ -58,7 +160,7 @@ impl Chip {
             device_type: 0x17,
             flash_size: 256 * 1024,
             eeprom_size: 0,
-            config_registers: vec![],
+            config_registers: ch32v30x_config_registers(),
             family: ChipFamily::CH32V,
         }
     }
@@ -71,7 +173,7 @@ impl Chip {
             device_type: 0x30,
             flash_size: 64 * 1024,
             eeprom_size: 0,
-            config_registers: vec![],
+            config_registers: ch32_config_registers(),
             family: ChipFamily::CH32V,
         }
     }
@@ -84,7 +186,7 @@ impl Chip {
             device_type: 0x30,
             flash_size: 128 * 1024,
             eeprom_size: 0,
-            config_registers: vec![],
+            config_registers: ch32_config_registers(),
             family: ChipFamily::CH32F,
         }
     }
@@ -110,7 +212,7 @@ impl Chip {
             device_type: 0x19,  // CH32V20x series device_type
             flash_size: 64 * 1024,
             eeprom_size: 0,
-            config_registers: vec![],
+            config_registers: ch32_config_registers(),
             family: ChipFamily::CH32V,
         }
     }
@@ -123,7 +225,7 @@ impl Chip {
             device_type: 0x21,  // CH32V00x series device_type
             flash_size: 16 * 1024,
             eeprom_size: 0,
-            config_registers: vec![],
+            config_registers: ch32v003_config_registers(),
             family: ChipFamily::CH32V003,
         }
     }
@@ -136,11 +238,24 @@ impl Chip {
             device_type: 0x23,  // CH32X03x series device_type
             flash_size: 62 * 1024,
             eeprom_size: 0,
-            config_registers: vec![],
+            config_registers: ch32_config_registers(),
             family: ChipFamily::CH32X035,
         }
     }
 
+    /// Create CH643 chip definition
+    pub fn ch643() -> Self {
+        Self {
+            name: "CH643".to_string(),
+            chip_id: 0x40,  // CH643W chip_id
+            device_type: 0x24,  // CH643 series device_type
+            flash_size: 62 * 1024,
+            eeprom_size: 0,
+            config_registers: ch32_config_registers(),
+            family: ChipFamily::CH643,
+        }
+    }
+
     /// Create CH549 chip definition
     pub fn ch549() -> Self {
         Self {
@@ -223,12 +338,56 @@ impl Chip {
         matches!(self.family, ChipFamily::CH32V | ChipFamily::CH32F)
     }
 
+    /// (OUT, IN) ISP endpoints of this family's bootloader, for devices whose
+    /// descriptors leave endpoint discovery guessing
+    pub fn isp_endpoints(&self) -> Option<(u8, u8)> {
+        match self.family {
+            ChipFamily::CH32X035 | ChipFamily::CH643 => Some((ISP_ENDPOINT_OUT, ISP_ENDPOINT_IN)),
+            _ => None,
+        }
+    }
+
+    /// Whether this chip satisfies a firmware's expected target.
+    ///
+    /// `expected` may be an exact chip name, a family name, or a name prefix such as
+    /// "CH32V" (matching any CH32V part); the comparison is case-insensitive.
+    pub fn matches_target(&self, expected: &str) -> bool {
+        let expected = expected.trim().to_ascii_uppercase();
+        if expected.is_empty() {
+            return false;
+        }
+        
+        self.name.to_ascii_uppercase().starts_with(&expected)
+            || format!("{:?}", self.family) == expected
+    }
+
     pub fn min_erase_sector_number(&self) -> u32 {
         1
     }
 
+    /// Minimum erase granularity in bytes.
+    ///
+    /// CH57x/CH58x/CH59x code flash erases in 4KiB blocks; the CH32 and 8051-based
+    /// parts erase in 1KiB sectors through the ISP erase command.
     pub fn sector_size(&self) -> u32 {
-        1024
+        match self.family {
+            ChipFamily::CH573 | ChipFamily::CH579 | ChipFamily::CH582 | ChipFamily::CH592 => 4096,
+            ChipFamily::CH32V | ChipFamily::CH32F | ChipFamily::CH32V003 | ChipFamily::CH32X035 |
+            ChipFamily::CH643 | ChipFamily::CH549 | ChipFamily::CH552 | ChipFamily::CH559 | ChipFamily::Unknown => 1024,
+        }
+    }
+
+    /// Number of erase command units needed to clear `len` bytes from the start of
+    /// code flash, rounded up to whole sectors and capped at the flash size.
+    ///
+    /// The Erase command always counts in 1KiB units, whatever the sector size.
+    pub fn erase_units(&self, len: u32) -> u32 {
+        let sector_size = self.sector_size();
+        let bytes = (len as u64).div_ceil(sector_size as u64) * sector_size as u64;
+        let units = bytes.div_ceil(ERASE_UNIT_SIZE as u64) as u32;
+        units
+            .min(self.flash_size.div_ceil(ERASE_UNIT_SIZE))
+            .max(self.min_erase_sector_number())
     }
 
     pub fn get_chip_info(&self) -> String {
@@ -244,12 +403,99 @@ impl Chip {
         }
     }
     
+    /// Format a chip UID for display.
+    ///
+    /// CH32 parts report their ESIG unique ID registers, shown as little-endian
+    /// 32-bit words (`1E53ABCD-99BC4A2C`); other families, and UIDs that are not
+    /// whole words, show the bytes in reported order (`CD-AB-53-1E`). An empty UID
+    /// formats as an empty string.
+    pub fn format_uid(&self, uid: &[u8]) -> String {
+        let ch32 = matches!(self.family,
+            ChipFamily::CH32V | ChipFamily::CH32F | ChipFamily::CH32V003 | ChipFamily::CH32X035 |
+            ChipFamily::CH643);
+        
+        if ch32 && uid.len().is_multiple_of(4) {
+            uid.chunks(4)
+                .map(|word| format!("{:08X}", u32::from_le_bytes([word[0], word[1], word[2], word[3]])))
+                .collect::<Vec<_>>()
+                .join("-")
+        } else {
+            uid.iter()
+                .map(|x| format!("{:02X}", x))
+                .collect::<Vec<_>>()
+                .join("-")
+        }
+    }
+    
+    /// Find a config register definition by name (case-insensitive)
+    pub fn config_register(&self, name: &str) -> Option<&ConfigRegister> {
+        self.config_registers
+            .iter()
+            .find(|reg| reg.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Decode config register bytes into (register name, field name, meaning) triples.
+    ///
+    /// `raw_config` holds the register block from a config read, starting at the
+    /// first register (i.e. with the 2-byte mask echo already stripped).
+    pub fn decode_config(&self, raw_config: &[u8]) -> Vec<(String, String, String)> {
+        let mut decoded = vec![];
+        
+        for reg in &self.config_registers {
+            let Some(bytes) = raw_config.get(reg.offset..reg.offset + 4) else {
+                continue;
+            };
+            let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            
+            if reg.fields.is_empty() {
+                let meaning = describe_value(value, &reg.explaination);
+                decoded.push((reg.name.clone(), reg.name.clone(), meaning));
+                continue;
+            }
+            
+            for field in &reg.fields {
+                let meaning = describe_value(field.extract(value), &field.explaination);
+                decoded.push((reg.name.clone(), field.name.clone(), meaning));
+            }
+        }
+        
+        decoded
+    }
+
     pub fn encryption_supported(&self) -> bool {
         matches!(self.family, 
                  ChipFamily::CH32V | ChipFamily::CH32F | 
                  ChipFamily::CH582 | ChipFamily::CH579 |
                  ChipFamily::CH573 | ChipFamily::CH592 |
-                 ChipFamily::CH32V003 | ChipFamily::CH32X035)
+                 ChipFamily::CH32V003 | ChipFamily::CH32X035 | ChipFamily::CH643)
+    }
+}
+
+impl ConfigField {
+    /// Extract this field's value from its register, `bit_range` being [high, low]
+    pub fn extract(&self, register: u32) -> u32 {
+        let [high, low] = self.bit_range;
+        let width = (high - low) as u32 + 1;
+        let mask = if width >= 32 { u32::MAX } else { (1 << width) - 1 };
+        (register >> low) & mask
+    }
+}
+
+/// Look up the explanation matching `value`, with "_" acting as the fallback entry
+fn describe_value(value: u32, explaination: &[(String, String)]) -> String {
+    explaination
+        .iter()
+        .find(|(key, _)| key == "_" || parse_number(key) == Some(value))
+        .map(|(_, text)| format!("0x{:X}: {}", value, text))
+        .unwrap_or_else(|| format!("0x{:X}", value))
+}
+
+/// Parse a decimal or 0x-prefixed hexadecimal number
+fn parse_number(s: &str) -> Option<u32> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
     }
 }
 
@@ -259,7 +505,7 @@ pub struct ChipDB {
 }
 
 impl ChipDB {
-    pub fn load() -> anyhow::Result<Self> {
+    pub fn load() -> Result<Self> {
         let mut chips = HashMap::new();
         
         let ch32v307 = Chip::ch32v307();
@@ -286,6 +532,10 @@ impl ChipDB {
         let ch32x035 = Chip::ch32x035();
         chips.insert((ch32x035.chip_id, ch32x035.device_type), ch32x035);
         
+        // Add CH643 support
+        let ch643 = Chip::ch643();
+        chips.insert((ch643.chip_id, ch643.device_type), ch643);
+        
         // Add CH549 support
         let ch549 = Chip::ch549();
         chips.insert((ch549.chip_id, ch549.device_type), ch549);
@@ -313,7 +563,14 @@ impl ChipDB {
         Ok(Self { chips })
     }
 
-    pub fn find_chip(&self, chip_id: u8, device_type: u8) -> anyhow::Result<Chip> {
+    /// All known chips, sorted by name
+    pub fn chips(&self) -> Vec<&Chip> {
+        let mut chips: Vec<&Chip> = self.chips.values().collect();
+        chips.sort_by(|a, b| a.name.cmp(&b.name));
+        chips
+    }
+
+    pub fn find_chip(&self, chip_id: u8, device_type: u8) -> Result<Chip> {
         self.chips
             .get(&(chip_id, device_type))
             .cloned()
@@ -328,7 +585,7 @@ impl ChipDB {
                     family: ChipFamily::Unknown,
                 })
             })
-            .ok_or_else(|| anyhow::anyhow!("Unknown chip: ID=0x{:02X}, Type=0x{:02X}", chip_id, device_type))
+            .ok_or_else(|| FlashError::UnsupportedChip(format!("ID=0x{:02X}, Type=0x{:02X}", chip_id, device_type)))
     }
 }
 
@@ -342,6 +599,21 @@ impl std::fmt::Display for Chip {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_format_uid() {
+        let uid = [0xcd, 0xab, 0x53, 0x1e, 0x2c, 0x4a, 0xbc, 0x99];
+        assert_eq!(Chip::ch32v203().format_uid(&uid), "1E53ABCD-99BC4A2C");
+        assert_eq!(Chip::ch582().format_uid(&uid), "CD-AB-53-1E-2C-4A-BC-99");
+        
+        // Longer UIDs keep the same grouping
+        let long = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c];
+        assert_eq!(Chip::ch32v307().format_uid(&long), "04030201-08070605-0C0B0A09");
+        assert_eq!(Chip::ch32v307().format_uid(&long[..10]), "01-02-03-04-05-06-07-08-09-0A");
+        
+        assert_eq!(Chip::ch32v203().format_uid(&[]), "");
+        assert_eq!(Chip::ch582().format_uid(&[]), "");
+    }
+
     #[test]
     fn test_chip_database_load() {
         let chip_db = ChipDB::load().expect("Failed to load chip database");
@@ -354,12 +626,73 @@ mod tests {
         assert!(chip_db.find_chip(0x30, 0x19).is_ok()); // CH32V203
         assert!(chip_db.find_chip(0x30, 0x21).is_ok()); // CH32V003
         assert!(chip_db.find_chip(0x50, 0x23).is_ok()); // CH32X035
+        assert!(chip_db.find_chip(0x40, 0x24).is_ok()); // CH643
         assert!(chip_db.find_chip(0x49, 0x11).is_ok()); // CH549
         assert!(chip_db.find_chip(0x52, 0x11).is_ok()); // CH552
         assert!(chip_db.find_chip(0x73, 0x13).is_ok()); // CH573
         assert!(chip_db.find_chip(0x79, 0x13).is_ok()); // CH579
         assert!(chip_db.find_chip(0x59, 0x22).is_ok()); // CH559
         assert!(chip_db.find_chip(0x92, 0x13).is_ok()); // CH592
+        
+        let names: Vec<&str> = chip_db.chips().iter().map(|chip| chip.name.as_str()).collect();
+        assert_eq!(names.len(), 14);
+        assert!(names.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_sector_size_per_family() {
+        let expected = [
+            (Chip::ch32v307(), 1024),
+            (Chip::ch32v103(), 1024),
+            (Chip::ch32f103(), 1024),
+            (Chip::ch32v203(), 1024),
+            (Chip::ch32v003(), 1024),
+            (Chip::ch32x035(), 1024),
+            (Chip::ch643(), 1024),
+            (Chip::ch549(), 1024),
+            (Chip::ch552(), 1024),
+            (Chip::ch559(), 1024),
+            (Chip::ch573(), 4096),
+            (Chip::ch579(), 4096),
+            (Chip::ch582(), 4096),
+            (Chip::ch592(), 4096),
+        ];
+        for (chip, sector_size) in expected {
+            assert_eq!(chip.sector_size(), sector_size, "Sector size should match for {}", chip.name);
+        }
+    }
+
+    #[test]
+    fn test_erase_units() {
+        // 1KiB sectors erase exactly the covered KiB
+        let chip = Chip::ch32v203();
+        assert_eq!(chip.erase_units(1), 1);
+        assert_eq!(chip.erase_units(1025), 2);
+        
+        // 4KiB sectors round the tail up to a whole block
+        let chip = Chip::ch582();
+        assert_eq!(chip.erase_units(1), 4);
+        assert_eq!(chip.erase_units(4097), 8);
+        assert_eq!(chip.erase_units(chip.flash_size), 448);
+        
+        // Never beyond the end of flash
+        let chip = Chip::ch579();
+        assert_eq!(chip.erase_units(chip.flash_size), 250);
+    }
+
+    #[test]
+    fn test_matches_target() {
+        let chip = Chip::ch32v307();
+        assert!(chip.matches_target("CH32V307"));
+        assert!(chip.matches_target("ch32v"));
+        assert!(chip.matches_target("CH32V3"));
+        assert!(!chip.matches_target("CH32V003"));
+        assert!(!chip.matches_target("CH32F"));
+        assert!(!chip.matches_target(""));
+        
+        let chip = Chip::ch582();
+        assert!(chip.matches_target("CH582"));
+        assert!(!chip.matches_target("CH32V"));
     }
 
     #[test]
@@ -399,6 +732,19 @@ mod tests {
         assert!(chip.encryption_supported());
     }
 
+    #[test]
+    fn test_ch643_chip_definition() {
+        let chip = Chip::ch643();
+        assert_eq!(chip.name, "CH643");
+        assert_eq!(chip.chip_id, 0x40);
+        assert_eq!(chip.device_type, 0x24);
+        assert_eq!(chip.flash_size, 62 * 1024);
+        assert!(matches!(chip.family, ChipFamily::CH643));
+        assert!(chip.encryption_supported());
+        assert_eq!(chip.isp_endpoints(), Some((0x02, 0x82)));
+        assert_eq!(Chip::ch32v203().isp_endpoints(), None);
+    }
+
     #[test]
     fn test_chip_info_display() {
         let ch32v203 = Chip::ch32v203();
@@ -450,4 +796,79 @@ mod tests {
         assert!(display.contains("CH32V203"));
         assert!(display.contains("0x")); // Contains hex formatting
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_decode_config() {
+        let chip = Chip {
+            config_registers: vec![
+                ConfigRegister {
+                    name: "RDPR_USER".to_string(),
+                    offset: 0x00,
+                    reset: None,
+                    enable_debug: None,
+                    fields: vec![
+                        ConfigField {
+                            name: "RDPR".to_string(),
+                            bit_range: [7, 0],
+                            explaination: explain(&[("0xa5", "Unprotected"), ("_", "Protected")]),
+                        },
+                        ConfigField {
+                            name: "IWDG_SW".to_string(),
+                            bit_range: [16, 16],
+                            explaination: explain(&[("1", "Software"), ("0", "Hardware")]),
+                        },
+                    ],
+                    explaination: vec![],
+                },
+                ConfigRegister {
+                    name: "WPR".to_string(),
+                    offset: 0x08,
+                    reset: None,
+                    enable_debug: None,
+                    fields: vec![],
+                    explaination: explain(&[("0xffffffff", "Unprotected")]),
+                },
+            ],
+            ..Chip::ch32v203()
+        };
+        
+        let raw = [0xa5, 0x5a, 0x01, 0xfe, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+        let decoded = chip.decode_config(&raw);
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[0], ("RDPR_USER".to_string(), "RDPR".to_string(), "0xA5: Unprotected".to_string()));
+        assert_eq!(decoded[1], ("RDPR_USER".to_string(), "IWDG_SW".to_string(), "0x1: Software".to_string()));
+        assert_eq!(decoded[2], ("WPR".to_string(), "WPR".to_string(), "0xFFFFFFFF: Unprotected".to_string()));
+        
+        // Fallback entry and unmatched values
+        let raw = [0x00, 0xff, 0x00, 0xff, 0, 0, 0, 0, 0x00, 0x00, 0x00, 0x00];
+        let decoded = chip.decode_config(&raw);
+        assert_eq!(decoded[0].2, "0x0: Protected");
+        assert_eq!(decoded[2].2, "0x0");
+        
+        // Registers beyond the supplied bytes are skipped
+        assert_eq!(chip.decode_config(&raw[..4]).len(), 2);
+    }
+
+    #[test]
+    fn test_family_config_fields() {
+        let meaning = |chip: &Chip, raw: &[u8], name: &str| {
+            chip.decode_config(raw).into_iter().find(|(_, field, _)| field == name).map(|(_, _, m)| m)
+        };
+        
+        // USER 0xbd: SRAM_CODE_MODE 0b10, STOP_RST enabled
+        let raw = [0xa5, 0x5a, 0xbd, 0x42, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+        let ch32v307 = Chip::ch32v307();
+        assert_eq!(meaning(&ch32v307, &raw, "SRAM_CODE_MODE").unwrap(), "0x2: CODE-256KB + RAM-64KB");
+        assert_eq!(meaning(&ch32v307, &raw, "STOP_RST").unwrap(), "0x0: Enable");
+        assert_eq!(meaning(&Chip::ch32v203(), &raw, "SRAM_CODE_MODE"), None);
+        
+        // USER 0x38: START_MODE set, RST_MODE 0b11
+        let raw = [0x3a, 0xc5, 0x38, 0xc7, 0, 0, 0, 0, 0xfe, 0xff, 0xff, 0xff];
+        let ch32v003 = Chip::ch32v003();
+        assert_eq!(meaning(&ch32v003, &raw, "RDPR").unwrap(), "0x3A: Protected");
+        assert_eq!(meaning(&ch32v003, &raw, "RST_MODE").unwrap(), "0x3: PD7 as GPIO");
+        assert_eq!(meaning(&ch32v003, &raw, "START_MODE").unwrap(), "0x1: Boot from bootloader");
+        assert_eq!(meaning(&ch32v003, &raw, "STOP_RST"), None);
+        assert_eq!(meaning(&ch32v003, &raw, "WPR").unwrap(), "0xFFFFFFFE: Some sectors are write-protected");
+    }
+}
@@ -0,0 +1,229 @@
+//! Error types
+//!
+//! This module defines the structured errors returned across the native library,
+//! along with the stable integer codes reported to Java
+
+use thiserror::Error;
+
+/// Stage of a full program cycle, reported when one of them fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashStage {
+    Unprotect = 1,
+    Erase = 2,
+    Program = 3,
+    Verify = 4,
+    Reset = 5,
+}
+
+impl std::fmt::Display for FlashStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            FlashStage::Unprotect => "unprotect",
+            FlashStage::Erase => "erase",
+            FlashStage::Program => "program",
+            FlashStage::Verify => "verify",
+            FlashStage::Reset => "reset",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Errors produced by the transport, protocol and flashing layers
+#[derive(Debug, Error)]
+pub enum FlashError {
+    #[error("USB timeout: {0}")]
+    UsbTimeout(String),
+
+    #[error("USB error: {0}")]
+    Usb(String),
+
+    #[error("No USB connection available")]
+    DeviceNotFound,
+
+    #[error("Unsupported device: VID=0x{vendor_id:04X}, PID=0x{product_id:04X}")]
+    UnsupportedDevice { vendor_id: u16, product_id: u16 },
+
+    #[error("Unsupported chip: {0}")]
+    UnsupportedChip(String),
+
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+
+    #[error("{command} failed: status=0x{status:02x}")]
+    CommandFailed { command: String, status: u8 },
+
+    #[error("Firmware targets {expected}, but the connected chip is {actual}")]
+    ChipMismatch { expected: String, actual: String },
+
+    #[error("Failed during {stage}: {source}")]
+    StageFailed { stage: FlashStage, source: Box<FlashError> },
+
+    #[error("Firmware of {size} bytes exceeds the {capacity} byte flash of {chip}")]
+    FirmwareTooLarge { chip: String, size: usize, capacity: u32 },
+
+    #[error("ISP key checksum mismatch: expected 0x{expected:02x}, got 0x{actual:02x}")]
+    IspKeyMismatch { expected: u8, actual: u8 },
+
+    #[error("Programming failed at address 0x{address:08x}")]
+    ProgramFailed { address: u32 },
+
+    #[error("Verification failed at address 0x{address:08x}")]
+    VerificationFailed { address: u32 },
+
+    #[error("{chip} does not support reading back code flash")]
+    ReadBackUnsupported { chip: String },
+
+    #[error("Invalid firmware: {0}")]
+    InvalidFirmware(String),
+
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
+    #[error("Invalid device handle: {0}")]
+    InvalidHandle(i32),
+
+    #[error("Operation did not finish within its {budget_ms} ms time budget")]
+    OperationTimeout { budget_ms: u64 },
+
+    #[error("Bootloader not responding after {attempts} identify attempts; check that BOOT0 is held high (or the BOOT button pressed) while the device is plugged in")]
+    BootloaderNotResponding { attempts: u32 },
+
+    #[error("USB permission lost; request permission for the device again")]
+    PermissionLost,
+
+    #[error("No interface has both a bulk IN and a bulk OUT endpoint; found {found}")]
+    NoUsableEndpoints { found: String },
+
+    #[error("JNI error: {0}")]
+    Jni(#[from] jni::errors::Error),
+}
+
+impl FlashError {
+    /// Stable code reported to Java; 0 is reserved for success.
+    ///
+    /// A failed stage reports the code of the underlying error. Codes 10 and 15
+    /// belonged to since-removed errors and are not reused.
+    pub fn code(&self) -> i32 {
+        match self {
+            FlashError::StageFailed { source, .. } => source.code(),
+            FlashError::UsbTimeout(_) => 1,
+            FlashError::Usb(_) => 2,
+            FlashError::DeviceNotFound => 3,
+            FlashError::UnsupportedDevice { .. } => 4,
+            FlashError::UnsupportedChip(_) => 5,
+            FlashError::Protocol(_) => 6,
+            FlashError::CommandFailed { .. } => 7,
+            FlashError::ProgramFailed { .. } => 8,
+            FlashError::VerificationFailed { .. } => 9,
+            FlashError::ReadBackUnsupported { .. } => 11,
+            FlashError::InvalidFirmware(_) => 12,
+            FlashError::InvalidArgument(_) => 13,
+            FlashError::InvalidHandle(_) => 14,
+            FlashError::Jni(_) => 16,
+            FlashError::ChipMismatch { .. } => 17,
+            FlashError::FirmwareTooLarge { .. } => 18,
+            FlashError::IspKeyMismatch { .. } => 19,
+            FlashError::OperationTimeout { .. } => 20,
+            FlashError::BootloaderNotResponding { .. } => 21,
+            FlashError::PermissionLost => 22,
+            FlashError::NoUsableEndpoints { .. } => 23,
+        }
+    }
+
+    /// Whether starting over after reconnecting may help: link-level failures,
+    /// as opposed to problems with the image, the chip or the arguments
+    pub fn is_transient(&self) -> bool {
+        match self {
+            FlashError::StageFailed { source, .. } => source.is_transient(),
+            FlashError::UsbTimeout(_)
+            | FlashError::Usb(_)
+            | FlashError::Protocol(_)
+            | FlashError::BootloaderNotResponding { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Stage that failed, if this error came from a full program cycle
+    pub fn stage(&self) -> Option<FlashStage> {
+        match self {
+            FlashError::StageFailed { stage, .. } => Some(*stage),
+            _ => None,
+        }
+    }
+
+    /// Shorthand for a command that returned a non-zero status
+    pub fn command_failed(command: impl Into<String>, status: u8) -> Self {
+        FlashError::CommandFailed { command: command.into(), status }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, FlashError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes_are_distinct() {
+        let errors = [
+            FlashError::UsbTimeout(String::new()),
+            FlashError::Usb(String::new()),
+            FlashError::DeviceNotFound,
+            FlashError::UnsupportedDevice { vendor_id: 0, product_id: 0 },
+            FlashError::UnsupportedChip(String::new()),
+            FlashError::Protocol(String::new()),
+            FlashError::command_failed("Erase", 0xfe),
+            FlashError::ProgramFailed { address: 0 },
+            FlashError::VerificationFailed { address: 0 },
+            FlashError::ReadBackUnsupported { chip: String::new() },
+            FlashError::InvalidFirmware(String::new()),
+            FlashError::InvalidArgument(String::new()),
+            FlashError::InvalidHandle(0),
+            FlashError::Jni(jni::errors::Error::NullPtr("test")),
+            FlashError::ChipMismatch { expected: String::new(), actual: String::new() },
+            FlashError::FirmwareTooLarge { chip: String::new(), size: 0, capacity: 0 },
+            FlashError::IspKeyMismatch { expected: 0, actual: 0 },
+            FlashError::OperationTimeout { budget_ms: 0 },
+            FlashError::BootloaderNotResponding { attempts: 0 },
+            FlashError::PermissionLost,
+            FlashError::NoUsableEndpoints { found: String::new() },
+        ];
+
+        let mut codes: Vec<i32> = errors.iter().map(FlashError::code).collect();
+        assert!(codes.iter().all(|&code| code > 0));
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+    }
+
+    #[test]
+    fn test_error_messages() {
+        assert_eq!(FlashError::VerificationFailed { address: 0x38 }.to_string(),
+                   "Verification failed at address 0x00000038");
+        assert_eq!(FlashError::command_failed("Erase", 0xfe).to_string(),
+                   "Erase failed: status=0xfe");
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        assert!(FlashError::UsbTimeout(String::new()).is_transient());
+        assert!(FlashError::StageFailed {
+            stage: FlashStage::Program,
+            source: Box::new(FlashError::Usb(String::new())),
+        }.is_transient());
+        assert!(!FlashError::FirmwareTooLarge { chip: String::new(), size: 0, capacity: 0 }.is_transient());
+        assert!(!FlashError::ChipMismatch { expected: String::new(), actual: String::new() }.is_transient());
+        assert!(!FlashError::PermissionLost.is_transient());
+    }
+
+    #[test]
+    fn test_stage_failure_reports_cause() {
+        let err = FlashError::StageFailed {
+            stage: FlashStage::Verify,
+            source: Box::new(FlashError::VerificationFailed { address: 0x38 }),
+        };
+        assert_eq!(err.stage(), Some(FlashStage::Verify));
+        assert_eq!(err.code(), FlashError::VerificationFailed { address: 0 }.code());
+        assert_eq!(err.to_string(), "Failed during verify: Verification failed at address 0x00000038");
+    }
+}
@@ -0,0 +1,283 @@
+//! Firmware image formats
+//!
+//! This module detects and parses raw binary, Intel HEX and ELF firmware images
+
+use crate::error::{FlashError, Result};
+use log::debug;
+use object::elf::{FileHeader32, PT_LOAD};
+use object::read::elf::{FileHeader, ProgramHeader};
+use object::Endianness;
+use serde::Serialize;
+use std::io::Read;
+
+/// Base address of the CH32 code flash alias; images linked there are
+/// programmed at the corresponding offset from zero
+const FLASH_ALIAS_BASE: u32 = 0x0800_0000;
+
+/// Size of the CH32 code flash alias window
+const FLASH_ALIAS_SIZE: u32 = 0x0100_0000;
+
+/// Supported firmware image formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum FirmwareFormat {
+    Binary,
+    IntelHex,
+    Elf,
+}
+
+impl FirmwareFormat {
+    /// Detect the image format from its leading bytes
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(b"\x7fELF") {
+            FirmwareFormat::Elf
+        } else if data.first() == Some(&b':') {
+            FirmwareFormat::IntelHex
+        } else {
+            FirmwareFormat::Binary
+        }
+    }
+}
+
+/// Map a linked address to a flash offset
+fn flash_offset(address: u32) -> u32 {
+    if (FLASH_ALIAS_BASE..FLASH_ALIAS_BASE + FLASH_ALIAS_SIZE).contains(&address) {
+        address - FLASH_ALIAS_BASE
+    } else {
+        address
+    }
+}
+
+/// Parse an Intel HEX image into (flash offset, data) segments, merging contiguous records
+pub fn read_ihex(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>> {
+    use ihex::Record;
+
+    let text = std::str::from_utf8(data)
+        .map_err(|_| FlashError::InvalidFirmware("Intel HEX image is not valid ASCII".to_string()))?;
+
+    let mut base_address = 0u32;
+    let mut segments: Vec<(u32, Vec<u8>)> = vec![];
+
+    for record in ihex::Reader::new(text) {
+        match record.map_err(|e| FlashError::InvalidFirmware(e.to_string()))? {
+            Record::Data { offset, value } => {
+                let address = flash_offset(base_address + offset as u32);
+                match segments.last_mut() {
+                    Some((start, bytes)) if *start + bytes.len() as u32 == address => {
+                        bytes.extend_from_slice(&value);
+                    }
+                    _ => segments.push((address, value)),
+                }
+            }
+            Record::ExtendedSegmentAddress(address) => base_address = (address as u32) * 16,
+            Record::ExtendedLinearAddress(address) => base_address = (address as u32) << 16,
+            Record::StartSegmentAddress { .. } | Record::StartLinearAddress(_) => {}
+            Record::EndOfFile => break,
+        }
+    }
+
+    segments.sort_by_key(|(address, _)| *address);
+    debug!("Parsed Intel HEX image: {} segments", segments.len());
+    Ok(segments)
+}
+
+/// Parse a 32-bit ELF image into (flash offset, data) segments from its loadable program headers
+pub fn read_elf(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>> {
+    let invalid = |e: object::read::Error| FlashError::InvalidFirmware(e.to_string());
+    let header = FileHeader32::<Endianness>::parse(data).map_err(invalid)?;
+    let endian = header.endian().map_err(invalid)?;
+
+    let mut segments = vec![];
+    for ph in header.program_headers(endian, data).map_err(invalid)? {
+        if ph.p_type(endian) != PT_LOAD || ph.p_filesz(endian) == 0 {
+            continue;
+        }
+
+        // Program at the load (physical) address so initialized data lands in flash
+        let bytes = ph.data(endian, data)
+            .map_err(|_| FlashError::InvalidFirmware("Invalid ELF segment data".to_string()))?;
+        segments.push((flash_offset(ph.p_paddr(endian)), bytes.to_vec()));
+    }
+
+    if segments.is_empty() {
+        return Err(FlashError::InvalidFirmware("ELF image has no loadable segments".to_string()));
+    }
+
+    segments.sort_by_key(|(address, _)| *address);
+    debug!("Parsed ELF image: {} segments", segments.len());
+    Ok(segments)
+}
+
+/// Load a firmware image of any supported format into (flash offset, data) segments.
+///
+/// Intel HEX and ELF are detected from their leading bytes; anything else is
+/// treated as a raw binary programmed at offset 0.
+pub fn load_firmware(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>> {
+    match FirmwareFormat::detect(data) {
+        FirmwareFormat::Binary => Ok(vec![(0, data.to_vec())]),
+        FirmwareFormat::IntelHex => read_ihex(data),
+        FirmwareFormat::Elf => read_elf(data),
+    }
+}
+
+/// Read a whole firmware file, `expected_len` being its size when known (e.g.
+/// from file metadata) so a file that ends early is reported rather than flashed
+pub fn read_firmware(reader: &mut impl Read, expected_len: Option<u64>) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(expected_len.unwrap_or(0) as usize);
+    reader
+        .read_to_end(&mut data)
+        .map_err(|e| FlashError::InvalidFirmware(format!("Failed to read firmware file: {}", e)))?;
+    
+    if let Some(expected) = expected_len.filter(|&len| len != data.len() as u64) {
+        return Err(FlashError::InvalidFirmware(
+            format!("Short read: got {} of {} bytes", data.len(), expected)));
+    }
+    if data.is_empty() {
+        return Err(FlashError::InvalidFirmware("Firmware file is empty".to_string()));
+    }
+    
+    debug!("Read {} byte firmware file", data.len());
+    Ok(data)
+}
+
+/// Offset one past the last byte of any segment, or 0 for an empty image
+pub fn image_end(segments: &[(u32, Vec<u8>)]) -> u32 {
+    segments
+        .iter()
+        .map(|(address, data)| address.saturating_add(data.len() as u32))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Flatten segments into one image starting at offset 0, filling gaps with the erased value 0xFF
+pub fn flatten(segments: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut image = vec![0xff; image_end(segments) as usize];
+    for (address, data) in segments {
+        let start = *address as usize;
+        image[start..start + data.len()].copy_from_slice(data);
+    }
+    image
+}
+
+/// Merge segments into sorted, disjoint runs whose bounds are widened to multiples
+/// of `align`, padding with 0xFF. Segments that overlap, touch, or share an
+/// aligned block end up in one run; where they overlap, later segments win.
+pub fn merge_segments(segments: &[(u32, Vec<u8>)], align: u32) -> Vec<(u32, Vec<u8>)> {
+    let mut order: Vec<&(u32, Vec<u8>)> = segments.iter().filter(|(_, data)| !data.is_empty()).collect();
+    order.sort_by_key(|(address, _)| *address);
+    
+    // Aligned (start, end) ranges of the runs
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for (address, data) in order {
+        let start = address - address % align;
+        let end = address.saturating_add(data.len() as u32).div_ceil(align).saturating_mul(align);
+        match ranges.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = (*last_end).max(end),
+            _ => ranges.push((start, end)),
+        }
+    }
+    
+    ranges
+        .into_iter()
+        .map(|(start, end)| {
+            let mut run = vec![0xff; (end - start) as usize];
+            // Copy in the original order so later segments overwrite earlier ones
+            for (address, data) in segments.iter().filter(|(address, _)| (start..end).contains(address)) {
+                let offset = (address - start) as usize;
+                run[offset..offset + data.len()].copy_from_slice(data);
+            }
+            (start, run)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal little-endian ELF32 with one loadable segment at `paddr`
+    fn elf_image(paddr: u32, data: &[u8]) -> Vec<u8> {
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        elf.extend_from_slice(&2u16.to_le_bytes()); // e_type: executable
+        elf.extend_from_slice(&0xf3u16.to_le_bytes()); // e_machine: RISC-V
+        elf.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        elf.extend_from_slice(&paddr.to_le_bytes()); // e_entry
+        elf.extend_from_slice(&52u32.to_le_bytes()); // e_phoff
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_shoff
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        elf.extend_from_slice(&52u16.to_le_bytes()); // e_ehsize
+        elf.extend_from_slice(&32u16.to_le_bytes()); // e_phentsize
+        elf.extend_from_slice(&1u16.to_le_bytes()); // e_phnum
+        elf.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum, e_shstrndx
+
+        let len = data.len() as u32;
+        for field in [1, 84, paddr, paddr, len, len, 5, 4] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+        elf.extend_from_slice(data);
+        elf
+    }
+
+    #[test]
+    fn test_load_binary() {
+        let data = [0x13, 0x00, 0x00, 0x00];
+        assert_eq!(FirmwareFormat::detect(&data), FirmwareFormat::Binary);
+        assert_eq!(load_firmware(&data).unwrap(), vec![(0, data.to_vec())]);
+    }
+
+    #[test]
+    fn test_load_intel_hex() {
+        // Extended linear address 0x0800 maps the data to flash offset 0x10
+        let hex = b":020000040800F2\n:0400100001020304E2\n:00000001FF\n";
+        assert_eq!(FirmwareFormat::detect(hex), FirmwareFormat::IntelHex);
+        assert_eq!(load_firmware(hex).unwrap(), vec![(0x10, vec![1, 2, 3, 4])]);
+
+        assert!(matches!(load_firmware(b":zz"), Err(FlashError::InvalidFirmware(_))));
+    }
+
+    #[test]
+    fn test_load_elf() {
+        let elf = elf_image(0x0800_0100, &[0xaa, 0xbb, 0xcc, 0xdd]);
+        assert_eq!(FirmwareFormat::detect(&elf), FirmwareFormat::Elf);
+        assert_eq!(load_firmware(&elf).unwrap(), vec![(0x100, vec![0xaa, 0xbb, 0xcc, 0xdd])]);
+
+        assert!(load_firmware(&elf[..20]).is_err());
+    }
+
+    #[test]
+    fn test_flatten_fills_gaps() {
+        let segments = vec![(2, vec![1, 2]), (6, vec![3])];
+        assert_eq!(image_end(&segments), 7);
+        assert_eq!(flatten(&segments), vec![0xff, 0xff, 1, 2, 0xff, 0xff, 3]);
+        assert!(flatten(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_merge_segments() {
+        // Overlapping, adjacent and same-block segments merge; the later one wins
+        let segments = vec![(0x10, vec![1; 4]), (0x12, vec![2; 4]), (0x16, vec![3]), (0x30, vec![4])];
+        let merged = merge_segments(&segments, 0x40);
+        assert_eq!(merged.len(), 1);
+        let (start, run) = &merged[0];
+        assert_eq!((*start, run.len()), (0, 0x40));
+        assert_eq!(&run[0x0f..0x18], &[0xff, 1, 1, 2, 2, 2, 2, 3, 0xff]);
+        assert_eq!(run[0x30], 4);
+        
+        // A segment straddling a block boundary covers both blocks; distant ones stay apart
+        let merged = merge_segments(&[(0x7e, vec![5; 4]), (0x1000, vec![6])], 0x40);
+        assert_eq!(merged.iter().map(|(a, d)| (*a, d.len())).collect::<Vec<_>>(), [(0x40, 0x80), (0x1000, 0x40)]);
+        assert_eq!(&merged[0].1[0x3e..0x42], &[5; 4]);
+        
+        assert!(merge_segments(&[], 0x40).is_empty());
+    }
+
+    #[test]
+    fn test_read_firmware() {
+        let file = [0x55u8; 300];
+        assert_eq!(read_firmware(&mut &file[..], Some(300)).unwrap().len(), 300);
+        assert_eq!(read_firmware(&mut &file[..], None).unwrap().len(), 300);
+
+        // The file ending before its reported size is an error
+        assert!(matches!(read_firmware(&mut &file[..200], Some(300)), Err(FlashError::InvalidFirmware(_))));
+        assert!(read_firmware(&mut &file[..0], None).is_err());
+    }
+}
@@ -3,45 +3,108 @@
 //! This native library provides JNI bindings for the WCH ISP functionality,
 //! replacing libusb dependencies with Android USB Host API integration.
 
-use jni::objects::{JClass, JByteArray, JObject};
-use jni::sys::{jint, jstring, jbyteArray, jboolean};
+use jni::objects::{JClass, JByteArray, JObject, JString, JValue};
+use jni::sys::{jint, jlong, jstring, jboolean, jbyteArray};
 use jni::JNIEnv;
 use log::{info, error};
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::fs::File;
+use std::os::fd::BorrowedFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+pub mod error;
 pub mod transport;
+pub mod usbfs;
 pub mod device;
 pub mod protocol;
 pub mod flashing;
+pub mod format;
+pub mod logging;
+#[cfg(feature = "simulator")]
+pub mod simulator;
 
-use crate::transport::AndroidUsbTransport;
-use crate::flashing::AndroidFlashing;
+use crate::device::ChipDB;
+use crate::transport::{AndroidUsbTransport, DeviceTransport, SUPPORTED_USB_IDS};
+use crate::error::FlashError;
+use crate::flashing::{AndroidFlashing, BoardOptions, BoardReport, BoardStage, FlashOptions, ProgressCallback, ResetMode};
 
-// Global state management for device handles
+// Global state management for device handles. Each instance has its own lock so
+// operations on different devices can run concurrently.
 lazy_static::lazy_static! {
-    static ref FLASHER_INSTANCES: Mutex<HashMap<i32, AndroidFlashing>> = Mutex::new(HashMap::new());
+    static ref FLASHER_INSTANCES: Mutex<HashMap<i32, Arc<Mutex<AndroidFlashing>>>> = Mutex::new(HashMap::new());
     static ref NEXT_HANDLE: Mutex<i32> = Mutex::new(1);
 }
 
+// The last failure is kept per thread, like errno, so boards driven from
+// different threads do not overwrite each other's error between the failing
+// call and getLastError.
+thread_local! {
+    static LAST_ERROR: RefCell<Option<(i32, String)>> = const { RefCell::new(None) };
+}
+
+/// Log a failure and record it for getLastError/getLastErrorCode on this thread,
+/// returning its code
+fn set_last_error(context: &str, error: impl Into<FlashError>) -> i32 {
+    let error = error.into();
+    let code = error.code();
+    error!("{}: {}", context, error);
+    LAST_ERROR.with_borrow_mut(|last| *last = Some((code, error.to_string())));
+    code
+}
+
+/// Look up the instance for a handle, holding the map lock only long enough to clone it
+fn flasher_instance(handle: jint) -> Option<Arc<Mutex<AndroidFlashing>>> {
+    FLASHER_INSTANCES.lock().unwrap().get(&handle).cloned()
+}
+
 /// Initialize the native library and logging
 #[no_mangle]
 pub extern "C" fn Java_com_wch_flasher_WchispNative_init(
     _env: JNIEnv,
     _class: JClass,
 ) -> jboolean {
-    // Initialize Android logger
-    android_logger::init_once(
-        android_logger::Config::default()
-            .with_max_level(log::LevelFilter::Debug)
-            .with_tag("wchisp-native")
-    );
+    // Initialize logging (logcat at Debug until setLogLevel changes it)
+    logging::init();
 
     info!("WCH ISP native library initialized");
     true as jboolean
 }
 
-/// Open USB device connection using Android USB Host API
+/// Set the log level (0 off, 1 error, 2 warn, 3 info, 4 debug, 5 trace)
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_setLogLevel(
+    _env: JNIEnv,
+    _class: JClass,
+    level: jint,
+) {
+    logging::set_level(level);
+    info!("Log level set to {}", log::max_level());
+}
+
+/// Route log records to `callback.log(int level, String message)` in addition to
+/// logcat; pass null to stop
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_setLogSink(
+    mut env: JNIEnv,
+    _class: JClass,
+    callback: JObject,
+) -> jboolean {
+    match logging::set_sink(&mut env, &callback) {
+        Ok(()) => true as jboolean,
+        Err(e) => {
+            set_last_error("Failed to set log sink", e);
+            false as jboolean
+        }
+    }
+}
+
+/// Open USB device connection using Android USB Host API.
+///
+/// Returns a positive handle, or the negated `FlashError` code on failure. -21
+/// (`BootloaderNotResponding`) means the device enumerated but never answered,
+/// usually because it was not started in ISP mode.
 #[no_mangle]
 pub extern "C" fn Java_com_wch_flasher_WchispNative_openDevice(
     mut env: JNIEnv,
@@ -56,27 +119,160 @@ pub extern "C" fn Java_com_wch_flasher_WchispNative_openDevice(
     
     // Validate that this is a supported device
     if !AndroidUsbTransport::is_supported_device(vendor_id as u16, product_id as u16) {
-        error!("Unsupported device: VID=0x{:04X}, PID=0x{:04X}", vendor_id, product_id);
-        return -1;
+        let e = FlashError::UnsupportedDevice { vendor_id: vendor_id as u16, product_id: product_id as u16 };
+        return -set_last_error("Failed to open device", e);
     }
     
     // Create transport and flashing instances
     let transport = AndroidUsbTransport::new(device_fd, vendor_id as u16, product_id as u16);
-    let mut flasher = match AndroidFlashing::new(transport) {
+    let mut flasher = match AndroidFlashing::new(DeviceTransport::Usb(transport)) {
         Ok(f) => f,
         Err(e) => {
-            error!("Failed to create flasher: {}", e);
-            return -1;
+            return -set_last_error("Failed to create flasher", e);
         }
     };
     
     // Initialize the flasher with the USB connection
-    if let Err(e) = flasher.initialize(&mut env, usb_connection) {
-        error!("Failed to initialize flasher: {}", e);
-        return -1;
+    if let Err(e) = flasher.initialize(&mut env, usb_connection, true) {
+        return -set_last_error("Failed to initialize flasher", e);
+    }
+    
+    let handle = register_instance(flasher);
+    info!("Device opened successfully with handle: {}", handle);
+    handle
+}
+
+/// Open a USB device like `openDevice`, but only identify the chip, skipping the
+/// config read. Suits a device picker that just needs the chip name; the
+/// bootloader version and UID stay unset until `refreshConfig`, which flashing
+/// also does on demand.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_openDeviceQuick(
+    mut env: JNIEnv,
+    _class: JClass,
+    device_fd: jint,
+    vendor_id: jint,
+    product_id: jint,
+    usb_connection: JObject,
+) -> jint {
+    info!("Quick-opening USB device with FD: {}, VID: 0x{:04X}, PID: 0x{:04X}",
+          device_fd, vendor_id as u16, product_id as u16);
+    
+    if !AndroidUsbTransport::is_supported_device(vendor_id as u16, product_id as u16) {
+        let e = FlashError::UnsupportedDevice { vendor_id: vendor_id as u16, product_id: product_id as u16 };
+        return -set_last_error("Failed to open device", e);
+    }
+    
+    let transport = AndroidUsbTransport::new(device_fd, vendor_id as u16, product_id as u16);
+    let mut flasher = match AndroidFlashing::new(DeviceTransport::Usb(transport)) {
+        Ok(f) => f,
+        Err(e) => {
+            return -set_last_error("Failed to create flasher", e);
+        }
+    };
+    
+    if let Err(e) = flasher.initialize(&mut env, usb_connection, false) {
+        return -set_last_error("Failed to initialize flasher", e);
+    }
+    
+    let handle = register_instance(flasher);
+    info!("Device opened successfully with handle: {}", handle);
+    handle
+}
+
+/// Read the bootloader version, UID and protection state of an open device,
+/// e.g. one opened with `openDeviceQuick`
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_refreshConfig(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jboolean {
+    info!("Refreshing config on handle: {}", handle);
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.refresh_config() {
+            Ok(()) => true as jboolean,
+            Err(e) => {
+                set_last_error("Failed to read chip configuration", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
     }
+}
+
+/// Open a USB device from its usbfs file descriptor alone, e.g. the one returned by
+/// `UsbDeviceConnection.getFileDescriptor()`. Transfers use usbfs ioctls directly
+/// instead of JNI calls; the descriptor must stay open until the device is closed.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_openDeviceFd(
+    _env: JNIEnv,
+    _class: JClass,
+    device_fd: jint,
+    vendor_id: jint,
+    product_id: jint,
+) -> jint {
+    info!("Opening USB device directly on FD: {}, VID: 0x{:04X}, PID: 0x{:04X}",
+          device_fd, vendor_id as u16, product_id as u16);
+    
+    if !AndroidUsbTransport::is_supported_device(vendor_id as u16, product_id as u16) {
+        let e = FlashError::UnsupportedDevice { vendor_id: vendor_id as u16, product_id: product_id as u16 };
+        return -set_last_error("Failed to open device", e);
+    }
+    
+    let transport = AndroidUsbTransport::new(device_fd, vendor_id as u16, product_id as u16);
+    let mut flasher = match AndroidFlashing::new(DeviceTransport::Usb(transport)) {
+        Ok(f) => f,
+        Err(e) => {
+            return -set_last_error("Failed to create flasher", e);
+        }
+    };
+    
+    if let Err(e) = flasher.initialize_direct() {
+        return -set_last_error("Failed to initialize flasher", e);
+    }
+    
+    let handle = register_instance(flasher);
+    info!("Device opened successfully with handle: {}", handle);
+    handle
+}
+
+/// Open a simulated CH32V203 that reports the given USB IDs, for UI development
+/// without hardware. Only available when built with the `simulator` feature.
+#[cfg(feature = "simulator")]
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_openSimulatedDevice(
+    _env: JNIEnv,
+    _class: JClass,
+    vendor_id: jint,
+    product_id: jint,
+) -> jint {
+    info!("Opening simulated device, VID: 0x{:04X}, PID: 0x{:04X}", vendor_id as u16, product_id as u16);
     
-    // Generate a handle and store the instance
+    let chip = crate::device::Chip::ch32v203();
+    let transport = simulator::SimulatedTransport::new(chip, vendor_id as u16, product_id as u16);
+    let mut flasher = match AndroidFlashing::new(DeviceTransport::Simulated(transport)) {
+        Ok(f) => f,
+        Err(e) => {
+            return -set_last_error("Failed to create flasher", e);
+        }
+    };
+    
+    if let Err(e) = flasher.connect() {
+        return -set_last_error("Failed to connect to simulated device", e);
+    }
+    
+    let handle = register_instance(flasher);
+    info!("Simulated device opened with handle: {}", handle);
+    handle
+}
+
+/// Allocate a handle for an opened device and store the instance under it
+fn register_instance(flasher: AndroidFlashing) -> jint {
     let handle = {
         let mut next_handle = NEXT_HANDLE.lock().unwrap();
         let handle = *next_handle;
@@ -84,34 +280,58 @@ pub extern "C" fn Java_com_wch_flasher_WchispNative_openDevice(
         handle
     };
     
-    {
-        let mut instances = FLASHER_INSTANCES.lock().unwrap();
-        instances.insert(handle, flasher);
-    }
-    
-    info!("Device opened successfully with handle: {}", handle);
+    let mut instances = FLASHER_INSTANCES.lock().unwrap();
+    instances.insert(handle, Arc::new(Mutex::new(flasher)));
     handle
 }
 
 /// Close USB device connection
 #[no_mangle]
 pub extern "C" fn Java_com_wch_flasher_WchispNative_closeDevice(
-    mut env: JNIEnv,
+    _env: JNIEnv,
     _class: JClass,
     handle: jint,
 ) -> jboolean {
     info!("Closing device handle: {}", handle);
     
-    let mut instances = FLASHER_INSTANCES.lock().unwrap();
-    if let Some(mut flasher) = instances.remove(&handle) {
-        if let Err(e) = flasher.close(&mut env) {
-            error!("Error closing flasher: {}", e);
+    let instance = FLASHER_INSTANCES.lock().unwrap().remove(&handle);
+    if let Some(flasher) = instance {
+        // Waits for any operation still running on this device
+        let mut flasher = flasher.lock().unwrap();
+        if let Err(e) = flasher.close() {
+            set_last_error("Error closing flasher", e);
             return false as jboolean;
         }
         info!("Device closed successfully");
         true as jboolean
     } else {
-        error!("Invalid device handle: {}", handle);
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Check whether an open device is still attached, e.g. to notice an unplugged
+/// cable before starting a flash. Returns false for a dead connection or unknown handle.
+///
+/// A device busy with another operation is reported as connected rather than
+/// waiting for the operation, which reports its own failures.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_isDeviceConnected(
+    mut env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jboolean {
+    if let Some(flasher) = flasher_instance(handle) {
+        let connected = match flasher.try_lock() {
+            Ok(flasher) => flasher.is_connected(&mut env),
+            Err(_) => true,
+        };
+        if !connected {
+            info!("Device handle {} is no longer connected", handle);
+        }
+        connected as jboolean
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
         false as jboolean
     }
 }
@@ -125,175 +345,1897 @@ pub extern "C" fn Java_com_wch_flasher_WchispNative_identifyChip(
 ) -> jstring {
     info!("Identifying chip on handle: {}", handle);
     
-    let instances = FLASHER_INSTANCES.lock().unwrap();
-    if let Some(flasher) = instances.get(&handle) {
+    if let Some(flasher) = flasher_instance(handle) {
+        let flasher = flasher.lock().unwrap();
         let chip_info = flasher.get_chip_info();
         match env.new_string(chip_info) {
             Ok(jstr) => jstr.into_raw(),
             Err(e) => {
-                error!("Failed to create Java string: {}", e);
+                set_last_error("Failed to create Java string", e);
                 std::ptr::null_mut()
             }
         }
     } else {
-        error!("Invalid device handle: {}", handle);
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
         std::ptr::null_mut()
     }
 }
 
-/// Flash firmware to the chip
+/// Report erase/program/verify progress on this device to
+/// `callback.onProgress(int stage, int done, int total)`, or stop when it is null.
+/// Stages use the `FlashStage` numbering (2 erase, 3 program, 4 verify).
 #[no_mangle]
-pub extern "C" fn Java_com_wch_flasher_WchispNative_flashFirmware(
-    mut env: JNIEnv,
+pub extern "C" fn Java_com_wch_flasher_WchispNative_setProgressCallback(
+    env: JNIEnv,
     _class: JClass,
     handle: jint,
-    firmware_data: jbyteArray,
+    callback: JObject,
 ) -> jboolean {
-    info!("Starting firmware flash on handle: {}", handle);
-    
-    // Convert Java byte array to Rust Vec<u8>
-    let firmware = {
-        // Create JByteArray from raw pointer
-        let array = unsafe { JByteArray::from_raw(firmware_data) };
-        match env.convert_byte_array(&array) {
-            Ok(data) => data,
+    let progress: Option<ProgressCallback> = if callback.is_null() {
+        None
+    } else {
+        let (vm, callback) = match env.get_java_vm().and_then(|vm| Ok((vm, env.new_global_ref(&callback)?))) {
+            Ok(refs) => refs,
             Err(e) => {
-                error!("Failed to convert firmware data: {}", e);
+                set_last_error("Failed to register progress callback", e);
                 return false as jboolean;
             }
-        }
+        };
+        Some(Box::new(move |stage, done, total| {
+            // Progress must never fail the operation, so callback errors are dropped
+            if let Ok(mut env) = vm.attach_current_thread() {
+                let args = [JValue::Int(stage as i32), JValue::Int(done as i32), JValue::Int(total as i32)];
+                if env.call_method(callback.as_obj(), "onProgress", "(III)V", &args).is_err() {
+                    let _ = env.exception_clear();
+                }
+            }
+        }))
     };
     
-    info!("Firmware size: {} bytes", firmware.len());
-    
-    let mut instances = FLASHER_INSTANCES.lock().unwrap();
-    if let Some(flasher) = instances.get_mut(&handle) {
-        match flasher.flash_firmware(&mut env, &firmware) {
-            Ok(()) => {
-                info!("Firmware flash completed successfully");
-                true as jboolean
-            }
-            Err(e) => {
-                error!("Firmware flash failed: {}", e);
-                false as jboolean
-            }
-        }
+    if let Some(flasher) = flasher_instance(handle) {
+        flasher.lock().unwrap().set_progress_callback(progress);
+        true as jboolean
     } else {
-        error!("Invalid device handle: {}", handle);  
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
         false as jboolean
     }
 }
 
-/// Erase chip flash memory
+/// Get the chip UID formatted as WCH tools show it, or an empty string if the chip reported none
 #[no_mangle]
-pub extern "C" fn Java_com_wch_flasher_WchispNative_eraseChip(
-    mut env: JNIEnv,
+pub extern "C" fn Java_com_wch_flasher_WchispNative_getChipUidFormatted(
+    env: JNIEnv,
     _class: JClass,
     handle: jint,
-) -> jboolean {
-    info!("Erasing chip on handle: {}", handle);
+) -> jstring {
+    let uid = if let Some(flasher) = flasher_instance(handle) {
+        let flasher = flasher.lock().unwrap();
+        flasher.chip_uid_formatted()
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        return std::ptr::null_mut();
+    };
     
-    let mut instances = FLASHER_INSTANCES.lock().unwrap();
-    if let Some(flasher) = instances.get_mut(&handle) {
-        // Calculate sectors to erase (full chip)
-        let chip = flasher.get_chip();
-        let sector_size = chip.sector_size();
-        let sectors = (chip.flash_size + sector_size - 1) / sector_size;
-        
-        match flasher.erase_flash(&mut env, sectors) {
-            Ok(()) => {
-                info!("Chip erase completed successfully");
-                true as jboolean
-            }
-            Err(e) => {
-                error!("Chip erase failed: {}", e);
-                false as jboolean
-            }
+    match env.new_string(uid) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
         }
+    }
+}
+
+/// Get the full Identify reply as hex, for reporting chips missing from the database
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_getRawIdentify(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jstring {
+    let raw = if let Some(flasher) = flasher_instance(handle) {
+        let flasher = flasher.lock().unwrap();
+        flasher.raw_identify_hex()
     } else {
-        error!("Invalid device handle: {}", handle);
-        false as jboolean
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        return std::ptr::null_mut();
+    };
+    
+    match env.new_string(raw) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
+        }
     }
 }
 
-/// Verify firmware on the chip
+/// Get the bootloader version, chip UID, chip ID, device type and family as JSON.
+///
+/// Uses what was read when the device was opened, so it never touches flash.
 #[no_mangle]
-pub extern "C" fn Java_com_wch_flasher_WchispNative_verifyFirmware(
-    mut env: JNIEnv,
+pub extern "C" fn Java_com_wch_flasher_WchispNative_getDeviceIdentity(
+    env: JNIEnv,
     _class: JClass,
     handle: jint,
-    firmware_data: jbyteArray,
-) -> jboolean {
-    info!("Verifying firmware on handle: {}", handle);
+) -> jstring {
+    info!("Getting device identity on handle: {}", handle);
     
-    let firmware = {
-        // Create JByteArray from raw pointer
-        let array = unsafe { JByteArray::from_raw(firmware_data) };
-        match env.convert_byte_array(&array) {
-            Ok(data) => data,
-            Err(e) => {
-                error!("Failed to convert firmware data: {}", e);
-                return false as jboolean;
-            }
+    let identity = if let Some(flasher) = flasher_instance(handle) {
+        let flasher = flasher.lock().unwrap();
+        flasher.device_identity()
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        return std::ptr::null_mut();
+    };
+    
+    let json = match serde_json::to_string(&identity) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Failed to serialize device identity: {}", e);
+            return std::ptr::null_mut();
         }
     };
     
-    let mut instances = FLASHER_INSTANCES.lock().unwrap();
-    if let Some(flasher) = instances.get_mut(&handle) {
-        match flasher.verify_firmware(&mut env, &firmware) {
-            Ok(()) => {
-                info!("Firmware verification completed successfully");
-                true as jboolean
-            }
-            Err(e) => {
-                error!("Firmware verification failed: {}", e);
-                false as jboolean
-            }
+    match env.new_string(json) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
         }
+    }
+}
+
+/// Run non-destructive connection diagnostics and return the report as JSON
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_runDiagnostics(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jstring {
+    info!("Running diagnostics on handle: {}", handle);
+    
+    let report = if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        flasher.diagnostics()
     } else {
-        error!("Invalid device handle: {}", handle);
-        false as jboolean
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        return std::ptr::null_mut();
+    };
+    
+    let json = match serde_json::to_string(&report) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Failed to serialize diagnostics report: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    
+    match env.new_string(json) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
+        }
     }
 }
 
-/// Reset the chip
+/// Get the USB IDs and manufacturer/product/serial strings of an open device as JSON.
+///
+/// `usb_device` is the matching `UsbDevice` and may be null; unavailable strings are null.
 #[no_mangle]
-pub extern "C" fn Java_com_wch_flasher_WchispNative_resetChip(
+pub extern "C" fn Java_com_wch_flasher_WchispNative_getUsbDeviceInfo(
     mut env: JNIEnv,
     _class: JClass,
     handle: jint,
-) -> jboolean {
-    info!("Resetting chip on handle: {}", handle);
+    usb_device: JObject,
+) -> jstring {
+    info!("Reading USB device info on handle: {}", handle);
     
-    let mut instances = FLASHER_INSTANCES.lock().unwrap();
-    if let Some(flasher) = instances.get_mut(&handle) {
-        match flasher.reset_chip(&mut env) {
-            Ok(()) => {
-                info!("Chip reset completed successfully");
-                true as jboolean
-            }
+    let (vendor_id, product_id, strings) = if let Some(flasher) = flasher_instance(handle) {
+        let flasher = flasher.lock().unwrap();
+        match flasher.usb_device_info(&mut env, &usb_device) {
+            Ok(info) => info,
             Err(e) => {
-                error!("Chip reset failed: {}", e);
-                false as jboolean
+                set_last_error("Failed to read USB device info", e);
+                return std::ptr::null_mut();
             }
         }
     } else {
-        error!("Invalid device handle: {}", handle);
-        false as jboolean
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        return std::ptr::null_mut();
+    };
+    
+    let json = serde_json::json!({
+        "vid": vendor_id,
+        "pid": product_id,
+        "manufacturer": strings.manufacturer,
+        "product": strings.product,
+        "serial": strings.serial,
+    });
+    
+    match env.new_string(json.to_string()) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
+        }
     }
 }
 
-/// Get last error message
+/// List the chips in the chip database and the supported USB IDs as JSON
 #[no_mangle]
-pub extern "C" fn Java_com_wch_flasher_WchispNative_getLastError(
+pub extern "C" fn Java_com_wch_flasher_WchispNative_listSupportedChips(
     env: JNIEnv,
     _class: JClass,
 ) -> jstring {
-    // Error handling implemented via proper Result propagation and logging
-    let error_msg = "No error";
+    info!("Listing supported chips");
     
-    match env.new_string(error_msg) {
+    let chip_db = match ChipDB::load() {
+        Ok(db) => db,
+        Err(e) => {
+            set_last_error("Failed to load chip database", e);
+            return std::ptr::null_mut();
+        }
+    };
+    
+    let chips: Vec<_> = chip_db.chips().into_iter()
+        .map(|chip| serde_json::json!({
+            "name": chip.name,
+            "family": chip.family,
+            "flashSize": chip.flash_size,
+            "eepromSize": chip.eeprom_size,
+            "chipId": chip.chip_id,
+            "deviceType": chip.device_type,
+            "encryptionSupported": chip.encryption_supported(),
+        }))
+        .collect();
+    let usb_ids: Vec<_> = SUPPORTED_USB_IDS.iter()
+        .map(|(vid, pid)| serde_json::json!({ "vid": vid, "pid": pid }))
+        .collect();
+    
+    let json = serde_json::json!({
+        "chips": chips,
+        "usbIds": usb_ids,
+    });
+    
+    match env.new_string(json.to_string()) {
         Ok(jstr) => jstr.into_raw(),
-        Err(_) => std::ptr::null_mut(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Flash firmware to the chip. Raw binaries are programmed from offset 0; HEX
+/// and ELF images with several segments have each segment programmed in place.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_flashFirmware(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    firmware_data: JByteArray,
+) -> jboolean {
+    info!("Starting firmware flash on handle: {}", handle);
+    
+    // Convert Java byte array to Rust Vec<u8>
+    let firmware = {
+        match env.convert_byte_array(&firmware_data) {
+            Ok(data) => data,
+            Err(e) => {
+                set_last_error("Failed to convert firmware data", e);
+                return false as jboolean;
+            }
+        }
+    };
+    
+    info!("Firmware size: {} bytes", firmware.len());
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.flash_image(&firmware) {
+            Ok(result) => {
+                info!("Firmware flash completed: {} bytes in {} ms", result.bytes_written, result.duration_ms);
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Firmware flash failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Flash only the sectors that differ from the image, then verify them. The number
+/// of sectors skipped is reported by `getLastFlashResult`.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_flashFirmwareDiff(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    firmware_data: JByteArray,
+) -> jboolean {
+    info!("Starting differential firmware flash on handle: {}", handle);
+    
+    let firmware = match env.convert_byte_array(&firmware_data) {
+        Ok(data) => data,
+        Err(e) => {
+            set_last_error("Failed to convert firmware data", e);
+            return false as jboolean;
+        }
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.flash_diff(&firmware) {
+            Ok(result) => {
+                info!("Differential flash completed: {} bytes written, {} sectors skipped in {} ms",
+                      result.bytes_written, result.sectors_skipped, result.duration_ms);
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Differential flash failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Flash firmware and, when `verify` is set, verify it before reporting success
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_flashFirmwareVerified(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    firmware_data: JByteArray,
+    verify: jboolean,
+) -> jboolean {
+    info!("Starting firmware flash on handle: {}, verify: {}", handle, verify != 0);
+    
+    let firmware = match env.convert_byte_array(&firmware_data) {
+        Ok(data) => data,
+        Err(e) => {
+            set_last_error("Failed to convert firmware data", e);
+            return false as jboolean;
+        }
+    };
+    
+    let options = FlashOptions {
+        verify_after: verify != 0,
+        ..Default::default()
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.flash_firmware_with_options(&firmware, &options) {
+            Ok(result) => {
+                info!("Firmware flash completed: {} bytes in {} ms", result.bytes_written, result.duration_ms);
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Firmware flash failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Flash firmware, verifying each chunk right after it is programmed when
+/// `verify_each_chunk` is set so a flaky link fails at the first bad chunk
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_flashFirmwareChunkVerified(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    firmware_data: JByteArray,
+    verify_each_chunk: jboolean,
+) -> jboolean {
+    info!("Starting firmware flash on handle: {}, verify each chunk: {}", handle, verify_each_chunk != 0);
+    
+    let firmware = match env.convert_byte_array(&firmware_data) {
+        Ok(data) => data,
+        Err(e) => {
+            set_last_error("Failed to convert firmware data", e);
+            return false as jboolean;
+        }
+    };
+    
+    let options = FlashOptions {
+        verify_each_chunk: verify_each_chunk != 0,
+        ..Default::default()
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.flash_firmware_with_options(&firmware, &options) {
+            Ok(result) => {
+                info!("Firmware flash completed: {} bytes in {} ms", result.bytes_written, result.duration_ms);
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Firmware flash failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Flash firmware and, when `preserve` is set, re-apply the USER, DATA and WPR
+/// option bytes afterwards if unprotecting a read-protected chip reset them
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_flashFirmwarePreservingConfig(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    firmware_data: JByteArray,
+    preserve: jboolean,
+) -> jboolean {
+    info!("Starting firmware flash on handle: {}, preserve config: {}", handle, preserve != 0);
+    
+    let firmware = match env.convert_byte_array(&firmware_data) {
+        Ok(data) => data,
+        Err(e) => {
+            set_last_error("Failed to convert firmware data", e);
+            return false as jboolean;
+        }
+    };
+    
+    let options = FlashOptions {
+        preserve_config: preserve != 0,
+        ..Default::default()
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.flash_firmware_with_options(&firmware, &options) {
+            Ok(result) => {
+                info!("Firmware flash completed: {} bytes in {} ms", result.bytes_written, result.duration_ms);
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Firmware flash failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Flash firmware, aborting with `OperationTimeout` if the whole flash takes longer
+/// than `budget_ms`; a budget of 0 or less uses the default scaled by image size
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_flashFirmwareWithBudget(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    firmware_data: JByteArray,
+    budget_ms: jlong,
+) -> jboolean {
+    info!("Starting firmware flash on handle: {}, budget: {} ms", handle, budget_ms);
+    
+    let firmware = match env.convert_byte_array(&firmware_data) {
+        Ok(data) => data,
+        Err(e) => {
+            set_last_error("Failed to convert firmware data", e);
+            return false as jboolean;
+        }
+    };
+    
+    let options = FlashOptions {
+        time_budget: (budget_ms > 0).then(|| Duration::from_millis(budget_ms as u64)),
+        ..Default::default()
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.flash_firmware_with_options(&firmware, &options) {
+            Ok(result) => {
+                info!("Firmware flash completed: {} bytes in {} ms", result.bytes_written, result.duration_ms);
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Firmware flash failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Flash firmware only if the connected chip matches the expected name or family.
+///
+/// Returns 0 on success, otherwise the `FlashError` code; a chip mismatch is
+/// reported before anything is erased.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_flashFirmwareForChip(
+    mut env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    firmware_data: JByteArray,
+    expected_name: JString,
+) -> jint {
+    info!("Starting firmware flash for expected chip on handle: {}", handle);
+    
+    let firmware = match env.convert_byte_array(&firmware_data) {
+        Ok(data) => data,
+        Err(e) => return set_last_error("Failed to convert firmware data", e),
+    };
+    
+    let expected_name: String = match env.get_string(&expected_name) {
+        Ok(name) => name.into(),
+        Err(e) => return set_last_error("Failed to convert expected chip name", e),
+    };
+    
+    let options = FlashOptions {
+        expected_chip: Some(expected_name).filter(|name| !name.trim().is_empty()),
+        ..Default::default()
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.flash_firmware_with_options(&firmware, &options) {
+            Ok(result) => {
+                info!("Firmware flash completed: {} bytes in {} ms", result.bytes_written, result.duration_ms);
+                0
+            }
+            Err(e) => set_last_error("Firmware flash failed", e),
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle))
+    }
+}
+
+/// Flash and verify firmware starting at a non-zero flash offset, preserving the region below it
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_flashFirmwareAt(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    address: jint,
+    firmware_data: JByteArray,
+) -> jboolean {
+    info!("Starting firmware flash at 0x{:08X} on handle: {}", address, handle);
+    
+    if address < 0 {
+        set_last_error("Firmware flash failed", FlashError::InvalidArgument(
+            format!("Invalid flash address: {}", address)));
+        return false as jboolean;
+    }
+    
+    let firmware = match env.convert_byte_array(&firmware_data) {
+        Ok(data) => data,
+        Err(e) => {
+            set_last_error("Failed to convert firmware data", e);
+            return false as jboolean;
+        }
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.flash_firmware_at(address as u32, &firmware) {
+            Ok(result) => {
+                info!("Firmware flash completed: {} bytes in {} ms", result.bytes_written, result.duration_ms);
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Firmware flash failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Unprotect, erase, program, verify and reset in a single call.
+///
+/// Returns 0 on success, the failing stage (1 unprotect, 2 erase, 3 program,
+/// 4 verify, 5 reset) otherwise, or a negated error code if the cycle could not
+/// start. The cause is available from getLastError/getLastErrorCode.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_programFull(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    firmware_data: JByteArray,
+) -> jint {
+    info!("Starting full program cycle on handle: {}", handle);
+    
+    let firmware = match env.convert_byte_array(&firmware_data) {
+        Ok(data) => data,
+        Err(e) => return -set_last_error("Failed to convert firmware data", e),
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.program_full(&firmware) {
+            Ok(()) => {
+                info!("Full program cycle completed successfully");
+                0
+            }
+            Err(e) => {
+                let stage = e.stage();
+                let code = set_last_error("Full program cycle failed", e);
+                stage.map_or(-code, |stage| stage as jint)
+            }
+        }
+    } else {
+        -set_last_error("Device lookup failed", FlashError::InvalidHandle(handle))
+    }
+}
+
+/// Open, flash, verify, reset and close a board in one call, for production jigs.
+///
+/// `options` is a JSON object, e.g. `{"verify": true, "targetChip": "CH32V203"}`;
+/// null or empty takes the defaults (verify on, no target check). No handle is
+/// registered and the device is closed whatever the outcome. Returns a JSON
+/// report with the chip, UID, each stage's result and the total duration; check
+/// its `passed` field. Returns null only if the arguments cannot be read.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_programBoard(
+    mut env: JNIEnv,
+    _class: JClass,
+    device_fd: jint,
+    vendor_id: jint,
+    product_id: jint,
+    usb_connection: JObject,
+    firmware_data: JByteArray,
+    options: JString,
+) -> jstring {
+    info!("Programming board on FD: {}, VID: 0x{:04X}, PID: 0x{:04X}",
+          device_fd, vendor_id as u16, product_id as u16);
+    let started = Instant::now();
+    
+    let firmware = match env.convert_byte_array(&firmware_data) {
+        Ok(data) => data,
+        Err(e) => {
+            set_last_error("Failed to convert firmware data", e);
+            return std::ptr::null_mut();
+        }
+    };
+    
+    let options: String = if options.is_null() {
+        String::new()
+    } else {
+        match env.get_string(&options) {
+            Ok(options) => options.into(),
+            Err(e) => {
+                set_last_error("Failed to convert board options", e);
+                return std::ptr::null_mut();
+            }
+        }
+    };
+    let options = if options.trim().is_empty() {
+        BoardOptions::default()
+    } else {
+        match serde_json::from_str::<BoardOptions>(&options) {
+            Ok(options) => options,
+            Err(e) => {
+                set_last_error("Invalid board options", FlashError::InvalidArgument(e.to_string()));
+                return std::ptr::null_mut();
+            }
+        }
+    };
+    
+    let mut report = BoardReport::default();
+    let opened = report.run_stage(BoardStage::Open, || {
+        if !AndroidUsbTransport::is_supported_device(vendor_id as u16, product_id as u16) {
+            return Err(FlashError::UnsupportedDevice { vendor_id: vendor_id as u16, product_id: product_id as u16 });
+        }
+        let transport = AndroidUsbTransport::new(device_fd, vendor_id as u16, product_id as u16);
+        let mut flasher = AndroidFlashing::new(DeviceTransport::Usb(transport))?;
+        flasher.initialize(&mut env, usb_connection, true)?;
+        Ok(flasher)
+    });
+    
+    // The flasher is never registered, so closing it here is all the cleanup there is
+    let outcome = opened.and_then(|mut flasher| {
+        let programmed = flasher.program_board(&firmware, &options, &mut report);
+        let closed = report.run_stage(BoardStage::Close, || flasher.close());
+        programmed.and(closed)
+    });
+    report.finish(started);
+    
+    match outcome {
+        Ok(()) => info!("Board programmed in {} ms", report.duration_ms),
+        Err(e) => {
+            set_last_error("Board programming failed", e);
+        }
+    }
+    
+    let json = match serde_json::to_string(&report) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Failed to serialize board report: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    
+    match env.new_string(json) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Erase chip flash memory
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_eraseChip(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jboolean {
+    info!("Erasing chip on handle: {}", handle);
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        // Calculate erase units covering the full chip
+        let chip = flasher.get_chip();
+        let sectors = chip.erase_units(chip.flash_size);
+        
+        match flasher.erase_flash(sectors) {
+            Ok(()) => {
+                info!("Chip erase completed successfully");
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Chip erase failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Erase a range of code flash sectors
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_eraseSectors(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    start_sector: jint,
+    count: jint,
+) -> jboolean {
+    info!("Erasing {} sectors from sector {} on handle: {}", count, start_sector, handle);
+    
+    if start_sector < 0 || count < 0 {
+        set_last_error("Sector erase failed", FlashError::InvalidArgument(
+            format!("Invalid sector range: start={}, count={}", start_sector, count)));
+        return false as jboolean;
+    }
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.erase_range(start_sector as u32, count as u32) {
+            Ok(()) => {
+                info!("Sector erase completed successfully");
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Sector erase failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Verify firmware on the chip
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_verifyFirmware(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    firmware_data: JByteArray,
+) -> jboolean {
+    info!("Verifying firmware on handle: {}", handle);
+    
+    let firmware = {
+        match env.convert_byte_array(&firmware_data) {
+            Ok(data) => data,
+            Err(e) => {
+                set_last_error("Failed to convert firmware data", e);
+                return false as jboolean;
+            }
+        }
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.verify_firmware(&firmware) {
+            Ok(()) => {
+                info!("Firmware verification completed successfully");
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Firmware verification failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Reset the chip
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_resetChip(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jboolean {
+    info!("Resetting chip on handle: {}", handle);
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.reset_chip() {
+            Ok(()) => {
+                info!("Chip reset completed successfully");
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Chip reset failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// End the ISP session: mode 1 resets into the application (as `resetChip`),
+/// mode 0 leaves the chip in its bootloader
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_resetChipMode(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    mode: jint,
+) -> jboolean {
+    info!("Resetting chip on handle: {} with mode {}", handle, mode);
+    
+    let Some(mode) = ResetMode::from_code(mode) else {
+        set_last_error("Chip reset failed", FlashError::InvalidArgument(format!("Unknown reset mode: {}", mode)));
+        return false as jboolean;
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.reset_with_mode(mode) {
+            Ok(()) => {
+                info!("Chip reset completed successfully");
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Chip reset failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Rebind an open handle to a new connection after the device re-enumerated.
+///
+/// A reset makes the device drop off the bus and invalidates its old
+/// `UsbDeviceConnection`; pass the connection to the re-enumerated device here
+/// to keep using the same handle.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_rebindConnection(
+    mut env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    usb_connection: JObject,
+) -> jboolean {
+    info!("Rebinding connection on handle: {}", handle);
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.rebind_connection(&mut env, usb_connection) {
+            Ok(()) => {
+                info!("Connection rebound successfully");
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Connection rebind failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Read bytes from the data EEPROM
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_readEeprom(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    address: jint,
+    length: jint,
+) -> jbyteArray {
+    info!("Reading EEPROM on handle: {}, address: 0x{:08X}, length: {}", handle, address, length);
+    
+    if address < 0 || length < 0 {
+        set_last_error("EEPROM read failed", FlashError::InvalidArgument(
+            format!("Invalid EEPROM range: address={}, length={}", address, length)));
+        return std::ptr::null_mut();
+    }
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.read_eeprom(address as u32, length as u32) {
+            Ok(data) => match env.byte_array_from_slice(&data) {
+                Ok(array) => array.into_raw(),
+                Err(e) => {
+                    set_last_error("Failed to create Java byte array", e);
+                    std::ptr::null_mut()
+                }
+            },
+            Err(e) => {
+                set_last_error("EEPROM read failed", e);
+                std::ptr::null_mut()
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        std::ptr::null_mut()
+    }
+}
+
+/// Write bytes to the data EEPROM
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_writeEeprom(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    eeprom_data: JByteArray,
+) -> jboolean {
+    info!("Writing EEPROM on handle: {}", handle);
+    
+    let data = match env.convert_byte_array(&eeprom_data) {
+        Ok(data) => data,
+        Err(e) => {
+            set_last_error("Failed to convert EEPROM data", e);
+            return false as jboolean;
+        }
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.write_eeprom(&data) {
+            Ok(()) => {
+                info!("EEPROM write completed successfully");
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("EEPROM write failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Program a data-flash image into the chip's EEPROM region
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_flashDataFlash(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    image_data: JByteArray,
+) -> jboolean {
+    info!("Flashing data flash on handle: {}", handle);
+    
+    let data = match env.convert_byte_array(&image_data) {
+        Ok(data) => data,
+        Err(e) => {
+            set_last_error("Failed to convert data flash image", e);
+            return false as jboolean;
+        }
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.flash_data_flash(&data) {
+            Ok(()) => {
+                info!("Data flash programming completed successfully");
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Data flash programming failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Read and decode the chip's config registers as JSON
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_readChipConfig(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jstring {
+    info!("Reading chip config on handle: {}", handle);
+    
+    let decoded = if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.read_decoded_config() {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                set_last_error("Failed to read chip config", e);
+                return std::ptr::null_mut();
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        return std::ptr::null_mut();
+    };
+    
+    let json = serde_json::Value::Array(
+        decoded
+            .into_iter()
+            .map(|(register, field, value)| serde_json::json!({
+                "register": register,
+                "field": field,
+                "value": value,
+            }))
+            .collect()
+    );
+    
+    match env.new_string(json.to_string()) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Write a named config register (option bytes)
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_writeConfigRegister(
+    mut env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    register_name: JString,
+    value: jint,
+) -> jboolean {
+    let register_name: String = match env.get_string(&register_name) {
+        Ok(name) => name.into(),
+        Err(e) => {
+            set_last_error("Failed to convert register name", e);
+            return false as jboolean;
+        }
+    };
+    
+    info!("Writing config register {} on handle: {}", register_name, handle);
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.write_config_register(&register_name, value as u32) {
+            Ok(()) => {
+                info!("Config register write completed successfully");
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Config register write failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Decode the USER option byte (watchdog, reset behaviour, boot mode) as JSON
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_getUserOptions(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jstring {
+    info!("Reading user options on handle: {}", handle);
+    
+    let options = if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.read_user_options() {
+            Ok(options) => options,
+            Err(e) => {
+                set_last_error("Failed to read user options", e);
+                return std::ptr::null_mut();
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        return std::ptr::null_mut();
+    };
+    
+    let json = match serde_json::to_string(&options) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Failed to serialize user options: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    
+    match env.new_string(json) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Set the pause between sending each command and reading its reply, in
+/// microseconds. Raise it for bootloaders that NAK reads issued too soon.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_setCommandDelay(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    micros: jint,
+) -> jboolean {
+    info!("Setting command delay on handle: {} to {} us", handle, micros);
+    
+    if micros < 0 {
+        let e = FlashError::InvalidArgument(format!("Negative command delay: {} us", micros));
+        set_last_error("Failed to set command delay", e);
+        return false as jboolean;
+    }
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.set_command_delay(Duration::from_micros(micros as u64)) {
+            Ok(()) => true as jboolean,
+            Err(e) => {
+                set_last_error("Failed to set command delay", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Set the seed sent with the ISP key command in place of the default 0x1e zero
+/// bytes. Every bootloader family accepts seeds of 0x1e to 0x3c bytes.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_setIspKeySeed(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    seed: JByteArray,
+) -> jboolean {
+    info!("Setting ISP key seed on handle: {}", handle);
+    
+    let seed = match env.convert_byte_array(&seed) {
+        Ok(seed) => seed,
+        Err(e) => {
+            set_last_error("Failed to convert key seed", e);
+            return false as jboolean;
+        }
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.set_isp_key_seed(&seed) {
+            Ok(()) => true as jboolean,
+            Err(e) => {
+                set_last_error("Failed to set ISP key seed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Write-protect (`protect`) or unprotect the sector groups in `mask` without
+/// touching read protection. Returns the new WPR value as an unsigned 32-bit
+/// value, or a negative error code.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_setWriteProtect(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    mask: jint,
+    protect: jboolean,
+) -> jlong {
+    info!("Setting write protect on handle: {}, mask: 0x{:08x}, protect: {}", handle, mask, protect != 0);
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.set_write_protect(mask as u32, protect != 0) {
+            Ok(wpr) => wpr as jlong,
+            Err(e) => -(set_last_error("Write protect update failed", e) as jlong),
+        }
+    } else {
+        -(set_last_error("Device lookup failed", FlashError::InvalidHandle(handle)) as jlong)
+    }
+}
+
+/// Restore the config registers to their factory defaults
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_resetConfig(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jboolean {
+    info!("Resetting config registers on handle: {}", handle);
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.reset_config_to_default() {
+            Ok(written) => {
+                info!("Config reset completed, {} registers written", written.len());
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Config reset failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Write the chip's debug-enable config values so a debugger can attach again.
+/// On read-protected parts this mass-erases code flash.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_enableDebug(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jboolean {
+    info!("Enabling debug access on handle: {}", handle);
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.enable_debug() {
+            Ok(changed) => {
+                info!("Debug access enabled (config changed: {})", changed);
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Enable debug failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Flash firmware read from an open file descriptor, e.g. `ParcelFileDescriptor.getFd()`,
+/// so large images need not be copied across JNI. The descriptor is duplicated and
+/// stays owned by the caller.
+///
+/// The file is still read into memory in full: telling HEX and ELF from a raw
+/// binary and decoding them needs the whole image. What this saves is the second
+/// copy through a Java byte array.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_flashFirmwareFromFd(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    file_fd: jint,
+) -> jboolean {
+    info!("Starting firmware flash from FD {} on handle: {}", file_fd, handle);
+    
+    let firmware = match read_firmware_fd(file_fd) {
+        Ok(data) => data,
+        Err(e) => {
+            set_last_error("Failed to read firmware file", e);
+            return false as jboolean;
+        }
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.flash_image(&firmware) {
+            Ok(_) => {
+                info!("Firmware flashed successfully");
+                true as jboolean
+            }
+            Err(e) => {
+                set_last_error("Firmware flash failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Read a whole firmware file from a descriptor the caller keeps ownership of
+fn read_firmware_fd(fd: jint) -> Result<Vec<u8>, FlashError> {
+    if fd < 0 {
+        return Err(FlashError::InvalidArgument(format!("Invalid file descriptor: {}", fd)));
+    }
+    
+    // SAFETY: the caller guarantees `fd` is open for the duration of this call;
+    // it is duplicated so closing our copy leaves theirs untouched
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    let mut file = fd.try_clone_to_owned()
+        .map(File::from)
+        .map_err(|e| FlashError::InvalidArgument(format!("Failed to duplicate file descriptor: {}", e)))?;
+    
+    // Pipes and sockets have no meaningful length, so only check regular files
+    let expected_len = file.metadata().ok().filter(|meta| meta.is_file()).map(|meta| meta.len());
+    format::read_firmware(&mut file, expected_len)
+}
+
+/// Flash firmware, re-claiming the interface, re-identifying and starting over
+/// with a fresh erase after a transient USB failure, up to `attempts` tries.
+///
+/// Returns JSON with `passed`, the `attempts` used, the flash `result` and the
+/// last `error`, or null if the flash could not start. Image and chip errors
+/// are not retried.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_flashFirmwareWithRetry(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    firmware_data: JByteArray,
+    attempts: jint,
+) -> jstring {
+    info!("Starting firmware flash with up to {} attempts on handle: {}", attempts, handle);
+    
+    let firmware = match env.convert_byte_array(&firmware_data) {
+        Ok(data) => data,
+        Err(e) => {
+            set_last_error("Failed to convert firmware data", e);
+            return std::ptr::null_mut();
+        }
+    };
+    
+    let (used, outcome) = if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        flasher.flash_firmware_with_retry(&firmware, attempts.max(1) as u32)
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        return std::ptr::null_mut();
+    };
+    
+    let (result, error) = match outcome {
+        Ok(result) => {
+            info!("Firmware flash completed after {} attempts: {} bytes in {} ms",
+                  used, result.bytes_written, result.duration_ms);
+            (Some(result), None)
+        }
+        Err(e) => {
+            let message = e.to_string();
+            set_last_error("Firmware flash failed", e);
+            (None, Some(message))
+        }
+    };
+    let json = serde_json::json!({
+        "passed": result.is_some(),
+        "attempts": used,
+        "result": result,
+        "error": error,
+    });
+    
+    match env.new_string(json.to_string()) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Flash and verify firmware, returning an audit record of the outcome as JSON.
+///
+/// The record is returned for failed flashes too; check its `passed` field.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_flashFirmwareWithReport(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    firmware_data: JByteArray,
+) -> jstring {
+    info!("Starting reported firmware flash on handle: {}", handle);
+    
+    let firmware = match env.convert_byte_array(&firmware_data) {
+        Ok(data) => data,
+        Err(e) => {
+            set_last_error("Failed to convert firmware data", e);
+            return std::ptr::null_mut();
+        }
+    };
+    
+    let report = if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        flasher.flash_firmware_with_report(&firmware)
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        return std::ptr::null_mut();
+    };
+    
+    let json = match serde_json::to_string(&report) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Failed to serialize flash report: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    
+    match env.new_string(json) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get a JSON summary of the last flash on this device: addresses, bytes written,
+/// sectors erased, duration and verify status
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_getLastFlashResult(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jstring {
+    info!("Getting last flash result on handle: {}", handle);
+    
+    let result = if let Some(flasher) = flasher_instance(handle) {
+        let flasher = flasher.lock().unwrap();
+        match flasher.last_flash_result() {
+            Some(result) => result.clone(),
+            None => {
+                set_last_error("No flash result", FlashError::InvalidArgument(
+                    "No flash has been programmed on this device".to_string()));
+                return std::ptr::null_mut();
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        return std::ptr::null_mut();
+    };
+    
+    let json = match serde_json::to_string(&result) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Failed to serialize flash result: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    
+    match env.new_string(json) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get JSON programming speed metrics of the last flash on this device: bytes,
+/// duration, bytes per second and min/avg/max Program command latency
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_getThroughputStats(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jstring {
+    info!("Getting throughput stats on handle: {}", handle);
+    
+    let stats = if let Some(flasher) = flasher_instance(handle) {
+        let flasher = flasher.lock().unwrap();
+        match flasher.throughput_stats() {
+            Some(stats) => stats.clone(),
+            None => {
+                set_last_error("No throughput stats", FlashError::InvalidArgument(
+                    "No flash has been programmed on this device".to_string()));
+                return std::ptr::null_mut();
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        return std::ptr::null_mut();
+    };
+    
+    let json = match serde_json::to_string(&stats) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Failed to serialize throughput stats: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    
+    match env.new_string(json) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Turn raw USB packet capture on or off; enabling it starts a fresh capture
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_setPacketCapture(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    enabled: jboolean,
+) -> jboolean {
+    info!("Setting packet capture to {} on handle: {}", enabled != 0, handle);
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.set_packet_capture(enabled != 0) {
+            Ok(()) => true as jboolean,
+            Err(e) => {
+                set_last_error("Packet capture update failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Get the captured USB packets as a JSON array of `{direction, offsetUs, data}`,
+/// with `data` in hex and `offsetUs` timed from the first packet
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_getPacketCapture(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jstring {
+    info!("Getting packet capture on handle: {}", handle);
+    
+    let packets = if let Some(flasher) = flasher_instance(handle) {
+        let flasher = flasher.lock().unwrap();
+        match flasher.captured_packets() {
+            Ok(packets) => packets,
+            Err(e) => {
+                set_last_error("Packet capture read failed", e);
+                return std::ptr::null_mut();
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        return std::ptr::null_mut();
+    };
+    
+    let json = match serde_json::to_string(&packets) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Failed to serialize packet capture: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    
+    match env.new_string(json) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Check whether code flash is read-protected, so the app can warn that
+/// unprotecting will erase the chip
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_isCodeFlashProtected(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jboolean {
+    if let Some(flasher) = flasher_instance(handle) {
+        let flasher = flasher.lock().unwrap();
+        flasher.is_code_flash_protected() as jboolean
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Read the boot option from the USER option byte (CH32V003: START_MODE, 0 = boot
+/// the application, 1 = start the bootloader first).
+/// Returns the value, or a negative error code; families without it fail as unsupported
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_getBootOption(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jint {
+    info!("Reading boot option on handle: {}", handle);
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.read_boot_option() {
+            Ok(value) => value as jint,
+            Err(e) => -set_last_error("Failed to read boot option", e),
+        }
+    } else {
+        -set_last_error("Device lookup failed", FlashError::InvalidHandle(handle))
+    }
+}
+
+/// Set the boot option in the USER option byte; see `getBootOption` for the values
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_setBootOption(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    value: jint,
+) -> jboolean {
+    info!("Setting boot option to {} on handle: {}", value, handle);
+    
+    if !(0..=u8::MAX as jint).contains(&value) {
+        set_last_error("Failed to set boot option", FlashError::InvalidArgument(
+            format!("Invalid boot option: {}", value)));
+        return false as jboolean;
+    }
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.write_boot_option(value as u8) {
+            Ok(()) => true as jboolean,
+            Err(e) => {
+                set_last_error("Failed to set boot option", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Clear read and write protection without flashing.
+/// Returns 0 if the chip was already unprotected, 1 if lifting read protection
+/// mass-erased it, 2 if only write protection was cleared, or a negative error code
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_unprotectFlash(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jint {
+    info!("Unprotecting code flash on handle: {}", handle);
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.unprotect() {
+            Ok(outcome) => {
+                info!("Unprotect completed: {:?}", outcome);
+                outcome as jint
+            }
+            Err(e) => -set_last_error("Unprotect failed", e),
+        }
+    } else {
+        -set_last_error("Device lookup failed", FlashError::InvalidHandle(handle))
+    }
+}
+
+/// Re-read the code flash protection state.
+/// Returns 1 if protected, 0 if not, or a negative error code
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_refreshProtectionStatus(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+) -> jint {
+    info!("Refreshing protection status on handle: {}", handle);
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.refresh_protection_status() {
+            Ok(protected) => protected as jint,
+            Err(e) => -set_last_error("Failed to read protection status", e),
+        }
+    } else {
+        -set_last_error("Device lookup failed", FlashError::InvalidHandle(handle))
+    }
+}
+
+/// Program bytes at a flash address whose target bytes are blank, leaving the
+/// rest of flash untouched. Returns 1 if it verified, 0 if not, or a negative error code
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_writeFlash(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    address: jint,
+    data: JByteArray,
+) -> jint {
+    info!("Writing flash at 0x{:08x} on handle: {}", address, handle);
+    
+    let data = match env.convert_byte_array(&data) {
+        Ok(data) => data,
+        Err(e) => return -set_last_error("Failed to convert data", e),
+    };
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.write_flash(address as u32, &data) {
+            Ok(verified) => verified as jint,
+            Err(e) => -set_last_error("Flash write failed", e),
+        }
+    } else {
+        -set_last_error("Device lookup failed", FlashError::InvalidHandle(handle))
+    }
+}
+
+/// Validate firmware against the connected chip without writing anything
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_validateFirmware(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    firmware_data: JByteArray,
+) -> jstring {
+    info!("Validating firmware on handle: {}", handle);
+    
+    let firmware = match env.convert_byte_array(&firmware_data) {
+        Ok(data) => data,
+        Err(e) => {
+            set_last_error("Failed to convert firmware data", e);
+            return std::ptr::null_mut();
+        }
+    };
+    
+    let report = if let Some(flasher) = flasher_instance(handle) {
+        let flasher = flasher.lock().unwrap();
+        flasher.validate_firmware(&firmware)
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        return std::ptr::null_mut();
+    };
+    
+    let json = match serde_json::to_string(&report) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Failed to serialize validation report: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    
+    match env.new_string(json) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(e) => {
+            set_last_error("Failed to create Java string", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Compute the CRC32 of the first `length` bytes of code flash.
+///
+/// Returns the CRC as a non-negative value, or the negated error code on failure.
+/// None of the supported bootloaders can read code flash back, so this currently
+/// returns -11 (`ReadBackUnsupported`); fall back to verifyFirmware on that code.
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_getFlashCrc32(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    length: jint,
+) -> jlong {
+    info!("Computing flash CRC32 on handle: {}, length: {}", handle, length);
+    
+    if length < 0 {
+        let e = FlashError::InvalidArgument(format!("Invalid CRC length: {}", length));
+        return -set_last_error("Flash CRC32 failed", e) as jlong;
+    }
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.read_flash(0, length as u32) {
+            Ok(data) => crc32fast::hash(&data) as jlong,
+            Err(e) => -set_last_error("Flash CRC32 failed", e) as jlong,
+        }
+    } else {
+        -set_last_error("Device lookup failed", FlashError::InvalidHandle(handle)) as jlong
+    }
+}
+
+/// Check whether a region of code flash is blank
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_isFlashBlank(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jint,
+    address: jint,
+    length: jint,
+) -> jboolean {
+    info!("Blank checking flash on handle: {}, address: 0x{:08X}, length: {}", handle, address, length);
+    
+    if address < 0 || length < 0 {
+        set_last_error("Blank check failed", FlashError::InvalidArgument(
+            format!("Invalid blank check range: address={}, length={}", address, length)));
+        return false as jboolean;
+    }
+    
+    if let Some(flasher) = flasher_instance(handle) {
+        let mut flasher = flasher.lock().unwrap();
+        match flasher.is_region_blank(address as u32, length as u32) {
+            Ok(blank) => blank as jboolean,
+            Err(e) => {
+                set_last_error("Blank check failed", e);
+                false as jboolean
+            }
+        }
+    } else {
+        set_last_error("Device lookup failed", FlashError::InvalidHandle(handle));
+        false as jboolean
+    }
+}
+
+/// Get the message of the most recent failure on the calling thread
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_getLastError(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let error_msg = LAST_ERROR.with_borrow(|last| match last {
+        Some((_, message)) => message.clone(),
+        None => "No error".to_string(),
+    });
+    
+    match env.new_string(error_msg) {
+        Ok(jstr) => jstr.into_raw(),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Get the code of the most recent failure on the calling thread (see
+/// `FlashError::code`), or 0 if none
+#[no_mangle]
+pub extern "C" fn Java_com_wch_flasher_WchispNative_getLastErrorCode(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    LAST_ERROR.with_borrow(|last| last.as_ref().map_or(0, |(code, _)| *code))
+}
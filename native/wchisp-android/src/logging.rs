@@ -0,0 +1,138 @@
+//! Logging
+//!
+//! This module installs a logger that writes to logcat and can optionally forward
+//! records to a Java callback, with a level that can be changed at runtime
+
+use std::cell::Cell;
+use std::sync::Mutex;
+
+use android_logger::{AndroidLogger, Config};
+use jni::{JNIEnv, JavaVM, objects::{GlobalRef, JObject, JValue}};
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::error::Result;
+
+/// Java method invoked on the sink object for each record: `void log(int level, String message)`
+const SINK_METHOD: &str = "log";
+const SINK_SIGNATURE: &str = "(ILjava/lang/String;)V";
+
+/// Java object receiving log records
+struct LogSink {
+    vm: JavaVM,
+    callback: GlobalRef,
+}
+
+/// Logger writing to logcat and an optional Java sink
+struct NativeLogger {
+    android: AndroidLogger,
+    sink: Mutex<Option<LogSink>>,
+}
+
+lazy_static::lazy_static! {
+    static ref LOGGER: NativeLogger = NativeLogger {
+        // No max level in the config, so logcat follows log::max_level()
+        android: AndroidLogger::new(Config::default().with_tag("wchisp-native")),
+        sink: Mutex::new(None),
+    };
+}
+
+thread_local! {
+    // Set while forwarding to Java, so logging done by JNI itself is not forwarded again
+    static IN_SINK: Cell<bool> = const { Cell::new(false) };
+}
+
+impl Log for NativeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        self.android.log(record);
+
+        if IN_SINK.with(|flag| flag.replace(true)) {
+            return;
+        }
+        if let Some(sink) = self.sink.lock().unwrap().as_ref() {
+            // Logging must never fail the caller, so sink errors are dropped
+            let _ = forward(sink, record);
+        }
+        IN_SINK.with(|flag| flag.set(false));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Deliver a record to the Java sink
+fn forward(sink: &LogSink, record: &Record) -> Result<()> {
+    let mut env = sink.vm.attach_current_thread()?;
+    let message = env.new_string(format!("{}: {}", record.target(), record.args()))?;
+
+    let result = env.call_method(
+        sink.callback.as_obj(),
+        SINK_METHOD,
+        SINK_SIGNATURE,
+        &[JValue::Int(record.level() as i32), JValue::Object(&message)],
+    );
+    if result.is_err() {
+        let _ = env.exception_clear();
+    }
+    Ok(())
+}
+
+/// Map a level from Java (0 off, 1 error, 2 warn, 3 info, 4 debug, 5 trace) to a filter
+fn level_filter(level: i32) -> LevelFilter {
+    match level {
+        i32::MIN..=0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Install the logger at Debug level; later calls leave the current configuration alone
+pub fn init() {
+    if log::set_logger(&*LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Debug);
+    }
+}
+
+/// Change the maximum level that is logged
+pub fn set_level(level: i32) {
+    log::set_max_level(level_filter(level));
+}
+
+/// Forward log records to `callback`'s `log(int, String)` method, or stop forwarding when it is null
+pub fn set_sink(env: &mut JNIEnv, callback: &JObject) -> Result<()> {
+    let sink = if callback.is_null() {
+        None
+    } else {
+        Some(LogSink {
+            vm: env.get_java_vm()?,
+            callback: env.new_global_ref(callback)?,
+        })
+    };
+
+    *LOGGER.sink.lock().unwrap() = sink;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_filter() {
+        assert_eq!(level_filter(-1), LevelFilter::Off);
+        assert_eq!(level_filter(0), LevelFilter::Off);
+        assert_eq!(level_filter(1), LevelFilter::Error);
+        assert_eq!(level_filter(3), LevelFilter::Info);
+        assert_eq!(level_filter(4), LevelFilter::Debug);
+        assert_eq!(level_filter(9), LevelFilter::Trace);
+    }
+}
@@ -2,12 +2,11 @@
 //! 
 //! This module implements the WCH ISP communication protocol
 
-use anyhow::Result;
+use crate::error::{FlashError, Result};
 use scroll::{Pwrite, LE};
-use log::{debug, error};
-use crate::transport::AndroidUsbTransport;
-use jni::JNIEnv;
-use std::time::Duration;
+use log::{debug, error, warn};
+use crate::transport::{Transport, DEFAULT_RECV_BUFFER_SIZE};
+use std::time::{Duration, Instant};
 
 /// ISP Command types
 #[repr(u8)]
@@ -27,7 +26,7 @@ pub enum CommandType {
 }
 
 /// ISP Command structure
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Command {
     pub cmd_type: CommandType,
     pub payload: Vec<u8>,
@@ -41,11 +40,13 @@ pub struct Response {
     pub payload: Vec<u8>,
 }
 
+/// Signature the bootloader expects after the chip ID and device type of Identify
+const IDENTIFY_SIGNATURE: &[u8; 16] = b"MCU ISP & WCH.CN";
+
 impl Command {
     pub fn identify(chip_id: u8, device_type: u8) -> Self {
-        let mut payload = vec![0; 6];
-        payload[0] = chip_id;
-        payload[1] = device_type;
+        let mut payload = vec![chip_id, device_type];
+        payload.extend_from_slice(IDENTIFY_SIGNATURE);
         
         Self {
             cmd_type: CommandType::Identify,
@@ -94,9 +95,9 @@ impl Command {
         }
     }
 
-    pub fn read_config(mask: u32) -> Self {
-        let mut payload = vec![0; 4];
-        payload.pwrite_with(mask, 0, LE).unwrap();
+    /// Read the config registers selected by `mask`, sent as a 16-bit field
+    pub fn read_config(mask: u8) -> Self {
+        let payload = vec![mask, 0x00];
         
         Self {
             cmd_type: CommandType::ReadConfig,
@@ -104,9 +105,10 @@ impl Command {
         }
     }
 
-    pub fn write_config(mask: u32, data: Vec<u8>) -> Self {
-        let mut payload = Vec::with_capacity(4 + data.len());
-        payload.extend_from_slice(&mask.to_le_bytes());
+    /// Write the config registers selected by `mask`, sent as a 16-bit field
+    pub fn write_config(mask: u8, data: Vec<u8>) -> Self {
+        let mut payload = Vec::with_capacity(2 + data.len());
+        payload.extend_from_slice(&[mask, 0x00]);
         payload.extend_from_slice(&data);
         
         Self {
@@ -155,12 +157,26 @@ impl Command {
         }
     }
 
-    /// Convert command to raw bytes for transmission
+    /// Convert command to raw bytes for transmission: type, 16-bit little-endian
+    /// payload length, payload.
+    ///
+    /// No command uses the third byte as a flag; it is the high length byte, zero
+    /// for every payload that fits in one packet. Fixed-size payloads are checked
+    /// against the length the bootloader expects for the command.
     pub fn into_raw(self) -> Result<Vec<u8>> {
+        if let Some(expected) = self.cmd_type.payload_len() {
+            if self.payload.len() != expected {
+                return Err(FlashError::Protocol(format!("{:?} payload must be {} bytes, got {}",
+                                                        self.cmd_type, expected, self.payload.len())));
+            }
+        }
+        let len = u16::try_from(self.payload.len())
+            .map_err(|_| FlashError::Protocol(format!("{:?} payload of {} bytes is too long",
+                                                      self.cmd_type, self.payload.len())))?;
+        
         let mut raw = Vec::with_capacity(3 + self.payload.len());
         raw.push(self.cmd_type as u8);
-        raw.push(self.payload.len() as u8);
-        raw.push(0x00); // Reserved byte
+        raw.extend_from_slice(&len.to_le_bytes());
         raw.extend_from_slice(&self.payload);
         
         debug!("Command packet: {} bytes", raw.len());
@@ -168,15 +184,10 @@ impl Command {
     }
 }
 
-impl Response {
-    /// Parse response from raw bytes
-    pub fn from_raw(raw: &[u8]) -> Result<Self> {
-        if raw.len() < 4 {
-            error!("Response too short: {} bytes", raw.len());
-            return Err(anyhow::anyhow!("Response too short"));
-        }
-
-        let cmd_type = match raw[0] {
+impl CommandType {
+    /// Decode a command type byte
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
             0xa1 => CommandType::Identify,
             0xa2 => CommandType::IspEnd,
             0xa3 => CommandType::IspKey,
@@ -188,19 +199,108 @@ impl Response {
             0xa9 => CommandType::DataErase,
             0xaa => CommandType::DataProgram,
             0xab => CommandType::DataRead,
-            _ => {
-                error!("Unknown command type: 0x{:02x}", raw[0]);
-                return Err(anyhow::anyhow!("Unknown command type: 0x{:02x}", raw[0]));
-            }
+            _ => return None,
+        })
+    }
+}
+
+/// Largest possible response: 4-byte header plus a 255-byte payload
+const MAX_RESPONSE_SIZE: usize = 4 + 255;
+
+/// Response timeout for identify, which the bootloader answers immediately
+const IDENTIFY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Response timeout for most commands
+const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Response timeout for erases when the caller does not size one to the region
+const DEFAULT_ERASE_TIMEOUT: Duration = Duration::from_millis(5000);
+
+impl CommandType {
+    /// Payload size of commands with a fixed layout; `None` for variable payloads
+    fn payload_len(self) -> Option<usize> {
+        match self {
+            // Chip ID, device type and the 16-byte signature
+            CommandType::Identify => Some(2 + IDENTIFY_SIGNATURE.len()),
+            // Reset flag
+            CommandType::IspEnd => Some(1),
+            // 32-bit count of 1KiB units
+            CommandType::Erase => Some(4),
+            // 16-bit register mask
+            CommandType::ReadConfig => Some(2),
+            // 32-bit address and 16-bit length
+            CommandType::DataRead => Some(6),
+            // Key seed, address + padding + data, mask + register data
+            CommandType::IspKey | CommandType::Program | CommandType::Verify | CommandType::WriteConfig
+            | CommandType::DataErase | CommandType::DataProgram => None,
+        }
+    }
+
+    /// Timeout used by `ProtocolHandler::transfer` for this command
+    pub fn default_timeout(self) -> Duration {
+        match self {
+            CommandType::Identify => IDENTIFY_TIMEOUT,
+            CommandType::Erase | CommandType::DataErase => DEFAULT_ERASE_TIMEOUT,
+            _ => DEFAULT_COMMAND_TIMEOUT,
+        }
+    }
+
+    /// Receive buffer size for this command's response; reads that return long
+    /// payloads take a whole response in one transfer where the link allows
+    fn recv_buffer_size(self) -> usize {
+        match self {
+            CommandType::ReadConfig | CommandType::DataRead => MAX_RESPONSE_SIZE,
+            _ => DEFAULT_RECV_BUFFER_SIZE,
+        }
+    }
+}
+
+/// Offset of the response header within received data.
+///
+/// Some CH32 bootloader versions send a status/ack byte ahead of the header; it
+/// is skipped when the first byte is not a command type but the second one is.
+fn frame_start(data: &[u8]) -> usize {
+    match (data.first(), data.get(1)) {
+        (Some(&first), Some(&second))
+            if CommandType::from_byte(first).is_none() && CommandType::from_byte(second).is_some() => 1,
+        _ => 0,
+    }
+}
+
+/// Payload length declared in a response header of at least 4 bytes.
+///
+/// Every bootloader family answers with type, status and a 16-bit little-endian
+/// payload length, mirroring the command header.
+fn payload_len(header: &[u8]) -> usize {
+    u16::from_le_bytes([header[2], header[3]]) as usize
+}
+
+impl Response {
+    /// Parse response from raw bytes, skipping a leading ack byte if present
+    pub fn from_raw(raw: &[u8]) -> Result<Self> {
+        let start = frame_start(raw);
+        if start > 0 {
+            debug!("Skipping leading ack byte 0x{:02x}", raw[0]);
+        }
+        let raw = &raw[start..];
+        
+        if raw.len() < 4 {
+            error!("Response too short: {} bytes", raw.len());
+            return Err(FlashError::Protocol("Response too short".to_string()));
+        }
+
+        let Some(cmd_type) = CommandType::from_byte(raw[0]) else {
+            error!("Unknown command type: 0x{:02x}", raw[0]);
+            return Err(FlashError::Protocol(format!("Unknown command type: 0x{:02x}", raw[0])));
         };
 
-        let payload_len = raw[1] as usize;
-        let status = raw[2];
+        let status = raw[1];
+        let payload_len = payload_len(raw);
         
         if raw.len() < 4 + payload_len {
             error!("Incomplete response payload: expected {}, got {}", 
                    4 + payload_len, raw.len());
-            return Err(anyhow::anyhow!("Incomplete response payload"));
+            return Err(FlashError::Protocol("Incomplete response payload".to_string()));
         }
 
         let payload = raw[4..4 + payload_len].to_vec();
@@ -224,61 +324,186 @@ impl Response {
     }
 }
 
+/// Whether a reply of type `reply` answers a command of type `cmd`
+fn same_command(reply: CommandType, cmd: CommandType) -> bool {
+    std::mem::discriminant(&reply) == std::mem::discriminant(&cmd)
+}
+
+/// Receive a complete response, reading further packets until the payload
+/// length declared in the header has fully arrived or `timeout` elapses.
+fn receive_response<F>(mut recv: F, timeout: Duration) -> Result<Vec<u8>>
+where
+    F: FnMut(Duration) -> Result<Vec<u8>>,
+{
+    let deadline = Instant::now() + timeout;
+    
+    let mut data = recv(timeout)?;
+    if data.is_empty() {
+        error!("No response received");
+        return Err(FlashError::UsbTimeout("No response received".to_string()));
+    }
+    
+    loop {
+        let start = frame_start(&data);
+        let expected = data.get(start..start + 4).map(|header| start + 4 + payload_len(header));
+        if matches!(expected, Some(expected) if data.len() >= expected) {
+            return Ok(data);
+        }
+        
+        let expected_str = expected.map_or_else(|| "?".to_string(), |e| e.to_string());
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            error!("Response timeout: received {} of {} bytes", data.len(), expected_str);
+            return Err(FlashError::UsbTimeout(format!("received {} of {} bytes", data.len(), expected_str)));
+        }
+        
+        match recv(remaining) {
+            Ok(more) => {
+                debug!("Received continuation packet: {} bytes", more.len());
+                data.extend_from_slice(&more);
+            }
+            Err(e) => {
+                error!("Response timeout: received {} of {} bytes: {}", data.len(), expected_str, e);
+                return Err(FlashError::UsbTimeout(format!("received {} of {} bytes", data.len(), expected_str)));
+            }
+        }
+    }
+}
+
+/// Default pause between sending a command and reading its reply over USB ISP.
+///
+/// USB bootloaders queue the reply on the IN endpoint, so this only needs to
+/// cover command processing. Some CH5xx (CH549/CH552/CH559) bootloaders NAK a
+/// read issued this soon after a command; raise the delay with
+/// [`ProtocolHandler::set_command_delay`] for those.
+pub const USB_COMMAND_DELAY: Duration = Duration::from_micros(100);
+
+/// Read timeout used while draining stale packets after an out-of-sequence reply
+const RESYNC_DRAIN_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// Most stale packets discarded in one resync, so a chattering device cannot stall it
+const RESYNC_MAX_PACKETS: usize = 16;
+
+/// Longest accepted inter-command delay
+pub const MAX_COMMAND_DELAY: Duration = Duration::from_secs(1);
+
 /// Protocol handler for WCH ISP communication
-pub struct ProtocolHandler;
+pub struct ProtocolHandler {
+    command_delay: Duration,
+}
+
+impl Default for ProtocolHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl ProtocolHandler {
     pub fn new() -> Self {
-        Self
+        Self { command_delay: USB_COMMAND_DELAY }
+    }
+    
+    pub fn command_delay(&self) -> Duration {
+        self.command_delay
     }
     
-    /// Send a command and receive response through transport layer
-    pub fn transfer(
+    /// Set the pause between sending a command and reading its reply
+    pub fn set_command_delay(&mut self, delay: Duration) -> Result<()> {
+        if delay > MAX_COMMAND_DELAY {
+            return Err(FlashError::InvalidArgument(
+                format!("Command delay of {:?} exceeds the {:?} limit", delay, MAX_COMMAND_DELAY)));
+        }
+        self.command_delay = delay;
+        Ok(())
+    }
+    
+    /// Send a command and receive response through transport layer, using the
+    /// command type's default timeout
+    pub fn transfer<T: Transport + ?Sized>(
         &self,
-        transport: &mut AndroidUsbTransport,
-        env: &mut JNIEnv,
+        transport: &mut T,
         cmd: Command
     ) -> Result<Response> {
-        self.transfer_with_timeout(transport, env, cmd, Duration::from_millis(1000))
+        let timeout = cmd.cmd_type.default_timeout();
+        self.transfer_with_timeout(transport, cmd, timeout)
     }
     
     /// Send a command with custom timeout
-    pub fn transfer_with_timeout(
+    pub fn transfer_with_timeout<T: Transport + ?Sized>(
         &self,
-        transport: &mut AndroidUsbTransport,
-        env: &mut JNIEnv,
+        transport: &mut T,
         cmd: Command,
         timeout: Duration
     ) -> Result<Response> {
+        let cmd_type = cmd.cmd_type;
+        let retry = cmd.clone();
+        let resp_data = self.exchange(transport, cmd, timeout)?;
+        let response = Response::from_raw(&resp_data)?;
+        
+        // A reply to some earlier command was left in the USB buffer: throw away
+        // whatever else is pending and send the command once more
+        if !same_command(response.cmd_type, cmd_type) {
+            warn!("Out-of-sequence reply: expected {:?}, got {:?} ({}); resyncing",
+                  cmd_type, response.cmd_type, hex::encode(&resp_data));
+            self.drain(transport);
+            let resp_data = self.exchange(transport, retry, timeout)?;
+            return self.parse_response(&resp_data, cmd_type);
+        }
+        
+        debug!("Command completed successfully");
+        Ok(response)
+    }
+    
+    /// Discard packets still queued on the IN endpoint, reading with a short
+    /// timeout until none arrive
+    fn drain<T: Transport + ?Sized>(&self, transport: &mut T) {
+        for _ in 0..RESYNC_MAX_PACKETS {
+            match transport.recv_raw(MAX_RESPONSE_SIZE, RESYNC_DRAIN_TIMEOUT) {
+                Ok(stale) if !stale.is_empty() => warn!("Discarding stale packet: {}", hex::encode(&stale)),
+                _ => return,
+            }
+        }
+        warn!("Still receiving after discarding {} stale packets", RESYNC_MAX_PACKETS);
+    }
+    
+    /// Send a command and receive the raw reply
+    fn exchange<T: Transport + ?Sized>(
+        &self,
+        transport: &mut T,
+        cmd: Command,
+        timeout: Duration
+    ) -> Result<Vec<u8>> {
         let cmd_type = cmd.cmd_type;
         let req = cmd.into_raw()?;
         
         debug!("Sending command: type=0x{:02x}, len={}", cmd_type as u8, req.len());
         
         // Send command
-        let bytes_sent = transport.send_raw(env, &req)?;
+        let bytes_sent = transport.send_raw(&req, timeout)?;
         if bytes_sent != req.len() {
             error!("Incomplete send: sent {} of {} bytes", bytes_sent, req.len());
-            return Err(anyhow::anyhow!("Incomplete command send"));
+            return Err(FlashError::Usb(format!("Incomplete command send: {} of {} bytes", bytes_sent, req.len())));
         }
         
-        // Small delay to ensure command is processed
-        std::thread::sleep(Duration::from_micros(100));
-        
-        // Receive response
-        let resp_data = transport.recv_raw(env, timeout)?;
-        if resp_data.is_empty() {
-            error!("No response received");
-            return Err(anyhow::anyhow!("No response received"));
+        // Give the bootloader time to process the command before reading
+        if !self.command_delay.is_zero() {
+            std::thread::sleep(self.command_delay);
         }
         
-        let response = Response::from_raw(&resp_data)?;
+        // Receive response, which may span several USB packets
+        let buffer_size = cmd_type.recv_buffer_size();
+        receive_response(|remaining| transport.recv_raw(buffer_size, remaining), timeout)
+    }
+    
+    /// Parse a reply and check it answers `cmd_type`
+    fn parse_response(&self, resp_data: &[u8], cmd_type: CommandType) -> Result<Response> {
+        let response = Response::from_raw(resp_data)?;
         
         // Verify response matches command
-        if std::mem::discriminant(&response.cmd_type) != std::mem::discriminant(&cmd_type) {
+        if !same_command(response.cmd_type, cmd_type) {
             error!("Response command type mismatch: expected {:?}, got {:?}", 
                    cmd_type, response.cmd_type);
-            return Err(anyhow::anyhow!("Response command type mismatch"));
+            return Err(FlashError::Protocol("Response command type mismatch".to_string()));
         }
         
         debug!("Command completed successfully");
@@ -286,34 +511,217 @@ impl ProtocolHandler {
     }
     
     /// Perform chip identification
-    pub fn identify_chip(
+    pub fn identify_chip<T: Transport + ?Sized>(
         &self,
-        transport: &mut AndroidUsbTransport,
-        env: &mut JNIEnv
+        transport: &mut T
     ) -> Result<(u8, u8)> {
+        let payload = self.identify_raw(transport)?;
+        let chip_id = payload[0];
+        let device_type = payload[1];
+        
+        debug!("Chip identified: ID=0x{:02x}, Type=0x{:02x}", chip_id, device_type);
+        Ok((chip_id, device_type))
+    }
+
+    /// Send Identify and return the whole reply payload, which starts with the
+    /// chip ID and device type
+    pub fn identify_raw<T: Transport + ?Sized>(
+        &self,
+        transport: &mut T
+    ) -> Result<Vec<u8>> {
         debug!("Identifying chip");
         
         let identify_cmd = Command::identify(0, 0);
-        let response = self.transfer(transport, env, identify_cmd)?;
+        let response = self.transfer(transport, identify_cmd)?;
         
         if !response.is_ok() {
             error!("Chip identification failed with status: 0x{:02x}", response.status);
-            return Err(anyhow::anyhow!("Chip identification failed"));
+            return Err(FlashError::command_failed("Chip identification", response.status));
         }
         
         if response.payload().len() < 2 {
             error!("Invalid identification response length: {}", response.payload().len());
-            return Err(anyhow::anyhow!("Invalid identification response"));
+            return Err(FlashError::Protocol("Invalid identification response".to_string()));
         }
         
-        let chip_id = response.payload()[0];
-        let device_type = response.payload()[1];
-        
-        debug!("Chip identified: ID=0x{:02x}, Type=0x{:02x}", chip_id, device_type);
-        Ok((chip_id, device_type))
+        Ok(response.payload)
     }
 }
 
 /// Constants for configuration register masks
-pub const CFG_MASK_ALL: u32 = 0x1F;
-pub const CFG_MASK_RDPR_USER_DATA_WPR: u32 = 0x07;
\ No newline at end of file
+pub const CFG_MASK_ALL: u8 = 0x1F;
+pub const CFG_MASK_RDPR_USER_DATA_WPR: u8 = 0x07;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+    use std::collections::VecDeque;
+
+    // Mock receive side that plays back scripted packets
+    fn scripted(packets: Vec<Vec<u8>>) -> impl FnMut(Duration) -> Result<Vec<u8>> {
+        let mut packets: VecDeque<Vec<u8>> = packets.into();
+        move |_| packets.pop_front().ok_or_else(|| FlashError::UsbTimeout("USB receive failed or timeout".to_string()))
+    }
+
+    #[test]
+    fn test_receive_single_packet() {
+        let packet = vec![0xa7, 0x00, 0x02, 0x00, 0x1f, 0x00];
+        let data = receive_response(scripted(vec![packet.clone()]), Duration::from_millis(100)).unwrap();
+        assert_eq!(data, packet);
+    }
+
+    #[test]
+    fn test_receive_fragmented_response() {
+        let mut full = vec![0xa7, 0x00, 0x1a, 0x00];
+        full.extend((0..0x1a).map(|i| i as u8));
+        
+        let packets = vec![full[..1].to_vec(), full[1..10].to_vec(), full[10..].to_vec()];
+        let data = receive_response(scripted(packets), Duration::from_millis(100)).unwrap();
+        assert_eq!(data, full);
+        
+        let response = Response::from_raw(&data).unwrap();
+        assert!(response.is_ok());
+        assert_eq!(response.payload().len(), 0x1a);
+    }
+
+    #[test]
+    fn test_receive_truncated_response_times_out() {
+        let packets = vec![vec![0xa7, 0x00, 0x1a, 0x00, 0x01, 0x02]];
+        let err = receive_response(scripted(packets), Duration::from_millis(100)).unwrap_err();
+        assert!(matches!(err, FlashError::UsbTimeout(_)));
+        assert_eq!(err.to_string(), "USB timeout: received 6 of 30 bytes");
+    }
+
+    #[test]
+    fn test_response_with_leading_ack() {
+        let plain = vec![0xa1, 0x00, 0x02, 0x00, 0x30, 0x19];
+        let mut prefixed = vec![0x00];
+        prefixed.extend_from_slice(&plain);
+        
+        for framing in [plain, prefixed] {
+            let data = receive_response(scripted(vec![framing[..3].to_vec(), framing[3..].to_vec()]),
+                                        Duration::from_millis(100)).unwrap();
+            let response = Response::from_raw(&data).unwrap();
+            assert!(matches!(response.cmd_type, CommandType::Identify));
+            assert!(response.is_ok());
+            assert_eq!(response.payload(), &[0x30, 0x19]);
+        }
+        
+        assert!(Response::from_raw(&[0x00, 0x00, 0x02, 0x00, 0x30, 0x19]).is_err());
+    }
+
+    #[test]
+    fn test_response_framing() {
+        // CH552 and CH32V203 identify replies share the type, status, 16-bit length layout
+        for (raw, ids) in [([0xa1, 0x00, 0x02, 0x00, 0x52, 0x11], [0x52, 0x11]),
+                           ([0xa1, 0x00, 0x02, 0x00, 0x30, 0x19], [0x30, 0x19])] {
+            let response = Response::from_raw(&raw).unwrap();
+            assert!(response.is_ok());
+            assert_eq!(response.payload(), &ids);
+        }
+        
+        // A failed command reports its status in byte 1
+        let response = Response::from_raw(&[0xa5, 0xfe, 0x02, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(response.status, 0xfe);
+        
+        // The high length byte counts, for replies longer than 255 bytes
+        let mut long = vec![0xa7, 0x00, 0x2c, 0x01];
+        long.extend(std::iter::repeat_n(0x5a, 0x12c));
+        let packets = vec![long[..64].to_vec(), long[64..].to_vec()];
+        let data = receive_response(scripted(packets), Duration::from_millis(100)).unwrap();
+        assert_eq!(Response::from_raw(&data).unwrap().payload().len(), 0x12c);
+    }
+
+    #[test]
+    fn test_receive_empty_response() {
+        assert!(receive_response(scripted(vec![vec![]]), Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_transfer_through_transport() {
+        let mut transport = MockTransport::new(vec![MockTransport::response(0xa1, 0x00, &[0x30, 0x19])]);
+        let handler = ProtocolHandler::new();
+        
+        let (chip_id, device_type) = handler.identify_chip(&mut transport).unwrap();
+        assert_eq!((chip_id, device_type), (0x30, 0x19));
+        assert_eq!(transport.sent.len(), 1);
+        assert_eq!(&transport.sent[0][..5], &[0xa1, 0x12, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_command_raw_bytes() {
+        let mut identify = vec![0xa1, 0x12, 0x00, 0x00, 0x00];
+        identify.extend_from_slice(b"MCU ISP & WCH.CN");
+        assert_eq!(Command::identify(0, 0).into_raw().unwrap(), identify);
+        
+        let mut isp_key = vec![0xa3, 0x1e, 0x00];
+        isp_key.extend_from_slice(&[0x00; 0x1e]);
+        assert_eq!(Command::isp_key(vec![0x00; 0x1e]).into_raw().unwrap(), isp_key);
+        
+        assert_eq!(Command::program(0x0800, 0x5a, vec![0x11, 0x22, 0x33]).into_raw().unwrap(),
+                   [0xa5, 0x08, 0x00, 0x00, 0x08, 0x00, 0x00, 0x5a, 0x11, 0x22, 0x33]);
+        assert_eq!(Command::program(0x1000, 0x00, vec![]).into_raw().unwrap(),
+                   [0xa5, 0x05, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00]);
+        
+        let config = vec![0xa5, 0x5a, 0xff, 0x00, 0x00, 0xff, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff];
+        let mut write_config = vec![0xa8, 0x0e, 0x00, 0x07, 0x00];
+        write_config.extend_from_slice(&config);
+        assert_eq!(Command::write_config(CFG_MASK_RDPR_USER_DATA_WPR, config).into_raw().unwrap(), write_config);
+        
+        assert_eq!(Command::read_config(CFG_MASK_ALL).into_raw().unwrap(), [0xa7, 0x02, 0x00, 0x1f, 0x00]);
+        assert_eq!(Command::erase(8).into_raw().unwrap(), [0xa4, 0x04, 0x00, 0x08, 0x00, 0x00, 0x00]);
+        assert_eq!(Command::isp_end(1).into_raw().unwrap(), [0xa2, 0x01, 0x00, 0x01]);
+        
+        // Payloads that do not match the command's layout are rejected
+        let bad = Command { cmd_type: CommandType::Erase, payload: vec![0x00; 2] };
+        assert!(bad.into_raw().is_err());
+    }
+
+    #[test]
+    fn test_command_timeouts_and_buffers() {
+        assert!(CommandType::Identify.default_timeout() < CommandType::Program.default_timeout());
+        assert!(CommandType::Program.default_timeout() < CommandType::Erase.default_timeout());
+        assert_eq!(CommandType::Program.recv_buffer_size(), DEFAULT_RECV_BUFFER_SIZE);
+        assert_eq!(CommandType::DataRead.recv_buffer_size(), MAX_RESPONSE_SIZE);
+    }
+
+    #[test]
+    fn test_command_delay() {
+        let mut handler = ProtocolHandler::new();
+        assert_eq!(handler.command_delay(), USB_COMMAND_DELAY);
+        
+        handler.set_command_delay(Duration::ZERO).unwrap();
+        let mut transport = MockTransport::new(vec![MockTransport::response(0xa1, 0x00, &[0x30, 0x19])]);
+        assert!(handler.identify_chip(&mut transport).is_ok());
+        
+        assert!(handler.set_command_delay(Duration::from_secs(2)).is_err());
+        assert_eq!(handler.command_delay(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_transfer_rejects_mismatched_response() {
+        let mut transport = MockTransport::new(vec![MockTransport::response(0xa4, 0x00, &[0x00, 0x00])]);
+        let handler = ProtocolHandler::new();
+        
+        assert!(handler.transfer(&mut transport, Command::read_config(CFG_MASK_ALL)).is_err());
+    }
+
+    #[test]
+    fn test_transfer_resyncs_after_stale_response() {
+        let mut transport = MockTransport::new(vec![
+            MockTransport::response(0xa4, 0x00, &[0x00, 0x00]),
+            MockTransport::response(0xa7, 0x00, &[0x1f, 0x00]),
+            vec![], // Nothing more pending
+            MockTransport::response(0xa7, 0x00, &[0x1f, 0x00, 0xa5, 0x5a]),
+        ]);
+        let handler = ProtocolHandler::new();
+        
+        // The stale erase reply and the queued config reply are discarded, then
+        // the command is sent again
+        let response = handler.transfer(&mut transport, Command::read_config(CFG_MASK_ALL)).unwrap();
+        assert_eq!(response.payload(), &[0x1f, 0x00, 0xa5, 0x5a]);
+        assert_eq!(transport.sent.len(), 2);
+        assert_eq!(transport.sent[0], transport.sent[1]);
+    }
+}
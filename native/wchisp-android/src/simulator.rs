@@ -0,0 +1,227 @@
+//! Simulated device
+//!
+//! This module provides a transport that emulates a WCH ISP bootloader in memory,
+//! so the flashing flow can be exercised without a board attached. It is only
+//! built with the `simulator` feature.
+
+use crate::device::{Chip, ERASE_UNIT_SIZE};
+use crate::error::{FlashError, Result};
+use crate::protocol::CommandType;
+use crate::transport::{DeviceStrings, Transport};
+use log::debug;
+use std::time::Duration;
+
+/// Time taken to answer any command
+const COMMAND_DELAY: Duration = Duration::from_millis(2);
+
+/// Additional time taken per KiB erased
+const ERASE_DELAY_PER_KIB: Duration = Duration::from_millis(5);
+
+/// Bootloader version reported in the config read reply
+const BOOTLOADER_VERSION: [u8; 4] = [0x00, 0x02, 0x06, 0x00];
+
+/// UID reported in the config read reply
+const CHIP_UID: [u8; 8] = [0xcd, 0xab, 0x53, 0x1e, 0x2c, 0x4a, 0xbc, 0x99];
+
+/// Status returned for commands the simulated bootloader rejects
+const STATUS_FAILED: u8 = 0xfe;
+
+/// In-memory WCH ISP bootloader for a single chip
+pub struct SimulatedTransport {
+    chip: Chip,
+    vendor_id: u16,
+    product_id: u16,
+    flash: Vec<u8>,
+    eeprom: Vec<u8>,
+    /// RDPR_USER, DATA and WPR registers as returned by a config read
+    config: [u8; 12],
+    /// Seed of the last ISP key command
+    key_seed: Vec<u8>,
+    pending: Option<Vec<u8>>,
+}
+
+impl SimulatedTransport {
+    /// Simulate an unprotected, blank `chip` reporting the given USB IDs
+    pub fn new(chip: Chip, vendor_id: u16, product_id: u16) -> Self {
+        Self {
+            flash: vec![0xff; chip.flash_size as usize],
+            eeprom: vec![0xff; chip.eeprom_size as usize],
+            chip,
+            vendor_id,
+            product_id,
+            config: [0xa5, 0x5a, 0xff, 0x00, 0x00, 0xff, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff],
+            key_seed: vec![],
+            pending: None,
+        }
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    pub fn product_id(&self) -> u16 {
+        self.product_id
+    }
+
+    /// Strings reported in place of the USB string descriptors
+    pub fn device_strings(&self) -> DeviceStrings {
+        DeviceStrings {
+            manufacturer: Some("WCH".to_string()),
+            product: Some(format!("{} (simulated)", self.chip.name)),
+            serial: Some(hex::encode_upper(CHIP_UID)),
+        }
+    }
+
+    /// XOR key the host derives from the UID, key seed and chip ID
+    fn xor_key(&self) -> [u8; 8] {
+        let checksum = CHIP_UID.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+        let len = self.key_seed.len();
+        let mut key = [checksum; 8];
+        for (byte, index) in key.iter_mut().zip([len / 7 * 4, len / 5, len / 7, len / 7 * 6, len / 7 * 3, len / 5 * 3, len / 7 * 5]) {
+            *byte ^= self.key_seed.get(index).copied().unwrap_or(0);
+        }
+        key[7] = key[0].wrapping_add(self.chip.chip_id);
+        key
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Vec<u8> {
+        let key = self.xor_key();
+        data.iter().enumerate().map(|(i, &b)| b ^ key[i % 8]).collect()
+    }
+
+    fn config_reply(&self) -> Vec<u8> {
+        let mut reply = vec![0u8; 2];
+        reply.extend_from_slice(&self.config);
+        reply.extend_from_slice(&BOOTLOADER_VERSION);
+        reply.extend_from_slice(&CHIP_UID);
+        reply
+    }
+
+    /// Split an address-prefixed Program/Verify payload into (address, decrypted data)
+    fn addressed_data(&self, payload: &[u8]) -> Option<(usize, Vec<u8>)> {
+        let address = u32::from_le_bytes(payload.get(..4)?.try_into().ok()?) as usize;
+        Some((address, self.decrypt(payload.get(5..)?)))
+    }
+
+    /// Execute a command, returning (status, payload) and how long the device takes
+    fn execute(&mut self, cmd: CommandType, payload: &[u8]) -> (u8, Vec<u8>, Duration) {
+        let ok = |payload: Vec<u8>| (0x00, payload, COMMAND_DELAY);
+        let failed = (STATUS_FAILED, vec![0x00, 0x00], COMMAND_DELAY);
+
+        match cmd {
+            CommandType::Identify => ok(vec![self.chip.chip_id, self.chip.device_type]),
+            CommandType::ReadConfig => ok(self.config_reply()),
+            CommandType::WriteConfig => match payload.get(2..14) {
+                Some(config) => {
+                    self.config.copy_from_slice(config);
+                    ok(vec![0x00, 0x00])
+                }
+                None => failed,
+            },
+            CommandType::IspKey => {
+                self.key_seed = payload.to_vec();
+                let checksum = self.xor_key().iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+                ok(vec![checksum, 0x00])
+            }
+            CommandType::Erase => {
+                let units = payload.get(..4).map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()));
+                let len = (units * ERASE_UNIT_SIZE).min(self.flash.len() as u32);
+                self.flash[..len as usize].fill(0xff);
+                let delay = COMMAND_DELAY + ERASE_DELAY_PER_KIB * (len / 1024);
+                (0x00, vec![0x00, 0x00], delay)
+            }
+            CommandType::Program | CommandType::DataProgram => {
+                let Some((address, data)) = self.addressed_data(payload) else {
+                    return failed;
+                };
+                let memory = if matches!(cmd, CommandType::Program) { &mut self.flash } else { &mut self.eeprom };
+                match memory.get_mut(address..address + data.len()) {
+                    Some(cells) => {
+                        // Programming can only clear bits, as on real flash
+                        for (cell, byte) in cells.iter_mut().zip(data) {
+                            *cell &= byte;
+                        }
+                        ok(vec![0x00, 0x00])
+                    }
+                    None => failed,
+                }
+            }
+            CommandType::Verify => {
+                let Some((address, data)) = self.addressed_data(payload) else {
+                    return failed;
+                };
+                match self.flash.get(address..address + data.len()) {
+                    Some(cells) => ok(vec![if cells == &data[..] { 0x00 } else { 0xf5 }, 0x00]),
+                    None => failed,
+                }
+            }
+            CommandType::DataErase => {
+                self.eeprom.fill(0xff);
+                (0x00, vec![0x00, 0x00], COMMAND_DELAY + ERASE_DELAY_PER_KIB * (self.eeprom.len() as u32 / 1024))
+            }
+            CommandType::DataRead => {
+                let address = payload.get(..4).map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap())) as usize;
+                let length = payload.get(4..6).map_or(0, |b| u16::from_le_bytes(b.try_into().unwrap())) as usize;
+                match self.eeprom.get(address..address + length) {
+                    Some(data) => ok(data.to_vec()),
+                    None => failed,
+                }
+            }
+            CommandType::IspEnd => ok(vec![0x00, 0x00]),
+        }
+    }
+}
+
+impl Transport for SimulatedTransport {
+    fn send_raw(&mut self, data: &[u8], _timeout: Duration) -> Result<usize> {
+        let cmd = data.first()
+            .and_then(|&b| CommandType::from_byte(b))
+            .ok_or_else(|| FlashError::Protocol("Simulated device received an unknown command".to_string()))?;
+        let payload = data.get(3..).unwrap_or_default();
+
+        let (status, reply, delay) = self.execute(cmd, payload);
+        debug!("Simulated {:?}: status=0x{:02x}, {} byte reply", cmd, status, reply.len());
+        std::thread::sleep(delay);
+
+        let mut raw = vec![cmd as u8, status];
+        raw.extend_from_slice(&(reply.len() as u16).to_le_bytes());
+        raw.extend_from_slice(&reply);
+        self.pending = Some(raw);
+        Ok(data.len())
+    }
+
+    fn recv_raw(&mut self, _buffer_size: usize, _timeout: Duration) -> Result<Vec<u8>> {
+        self.pending
+            .take()
+            .ok_or_else(|| FlashError::UsbTimeout("Simulated device has no pending response".to_string()))
+    }
+
+    fn endpoints(&self) -> Option<(u8, u8)> {
+        Some((0x02, 0x82))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flashing::{AndroidFlashing, FlashOptions};
+
+    #[test]
+    fn test_simulated_flash_cycle() {
+        let transport = SimulatedTransport::new(Chip::ch32v203(), 0x4348, 0x55e0);
+        let mut flashing = AndroidFlashing::new(transport).unwrap();
+        flashing.connect().expect("simulated device should identify");
+        assert_eq!(flashing.get_chip().name, "CH32V203");
+
+        let firmware: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+        let options = FlashOptions { verify_after: true, ..Default::default() };
+        let result = flashing.flash_firmware_with_options(&firmware, &options).expect("simulated flash should succeed");
+        assert_eq!(result.bytes_written, 300);
+        assert_eq!(result.verified, Some(true));
+
+        // Verifying other data fails where it differs
+        let mut other = firmware.clone();
+        other[200] ^= 0xff;
+        assert!(matches!(flashing.verify_firmware(&other), Err(FlashError::VerificationFailed { .. })));
+    }
+}
@@ -2,60 +2,234 @@
 //! 
 //! This module replaces the libusb-based transport with Android USB Host API integration
 
-use std::time::Duration;
-use anyhow::Result;
-use log::{debug, info};
-use jni::{JNIEnv, objects::JObject};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use crate::error::{FlashError, Result};
+use crate::usbfs;
+use log::{debug, error, info, warn};
+use jni::{JNIEnv, JavaVM, objects::{GlobalRef, JObject, JString}};
+use serde::Serialize;
+
+/// Bulk transfer endpoint type (UsbConstants.USB_ENDPOINT_XFER_BULK)
+const USB_ENDPOINT_XFER_BULK: i32 = 2;
+
+/// Inbound endpoint direction (UsbConstants.USB_DIR_IN)
+const USB_DIR_IN: i32 = 0x80;
+
+/// Vendor-specific interface class (UsbConstants.USB_CLASS_VENDOR_SPEC)
+const USB_CLASS_VENDOR_SPEC: i32 = 0xff;
+
+/// Standard GET_DESCRIPTOR request code
+const USB_REQ_GET_DESCRIPTOR: i32 = 0x06;
+
+/// Standard GET_STATUS request code
+const USB_REQ_GET_STATUS: i32 = 0x00;
+
+/// Timeout of the liveness probe; a present device answers GET_STATUS at once
+const LIVENESS_TIMEOUT_MS: i32 = 100;
+
+/// String descriptor type
+const USB_DT_STRING: u8 = 0x03;
+
+/// Device descriptor type
+const USB_DT_DEVICE: u8 = 0x01;
+
+/// Interface descriptor type
+const USB_DT_INTERFACE: u8 = 0x04;
+
+/// Endpoint descriptor type
+const USB_DT_ENDPOINT: u8 = 0x05;
+
+/// A failed bulk transfer returning sooner than this did not wait for its timeout
+const IMMEDIATE_FAILURE_WINDOW: Duration = Duration::from_millis(10);
+
+/// Back-to-back immediate failures taken to mean USB permission was revoked
+const PERMISSION_LOST_FAILURES: u32 = 3;
+
+/// Language used when the device does not report one (US English)
+const DEFAULT_LANG_ID: u16 = 0x0409;
+
+/// Packet size of the standard 64-byte USB ISP endpoints
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 64;
+
+/// Receive buffer size holding one standard ISP packet
+pub const DEFAULT_RECV_BUFFER_SIZE: usize = 64;
+
+/// Bulk OUT endpoint of the ISP interface in programming mode
+pub const ISP_ENDPOINT_OUT: u8 = 0x02;
+
+/// Bulk IN endpoint of the ISP interface in programming mode
+pub const ISP_ENDPOINT_IN: u8 = 0x82;
+
+/// Packets kept by the packet capture; the oldest are dropped beyond this
+pub const MAX_CAPTURED_PACKETS: usize = 4096;
+
+/// (vendor ID, product ID) pairs of the WCH ISP bootloaders this library drives
+pub const SUPPORTED_USB_IDS: [(u16, u16); 2] = [(0x4348, 0x55e0), (0x1a86, 0x55e0)];
+
+/// Raw packet transport used by the ISP protocol layer
+pub trait Transport {
+    /// Send a raw packet, waiting at most `timeout`, returning the number of bytes sent
+    fn send_raw(&mut self, data: &[u8], timeout: Duration) -> Result<usize>;
+
+    /// Receive up to `buffer_size` bytes in a single transfer, waiting at most `timeout`
+    fn recv_raw(&mut self, buffer_size: usize, timeout: Duration) -> Result<Vec<u8>>;
+
+    /// Largest packet the link carries in one transfer
+    fn max_packet_size(&self) -> usize {
+        DEFAULT_MAX_PACKET_SIZE
+    }
+
+    /// (OUT, IN) endpoint addresses in use, if the link is bound to a device
+    fn endpoints(&self) -> Option<(u8, u8)> {
+        None
+    }
+
+    /// Switch to the (OUT, IN) endpoints known for the identified chip if the
+    /// link could not tell them apart on its own
+    fn apply_endpoint_hint(&mut self, _endpoints: (u8, u8)) {}
+}
+
+/// Manufacturer, product and serial strings of a USB device; any may be unavailable
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceStrings {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+}
+
+/// Direction of a captured packet, relative to the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Out,
+    In,
+}
+
+/// One captured packet, timed from the first packet in the capture
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CapturedPacket {
+    pub direction: Direction,
+    pub offset_us: u64,
+    pub data: String,
+}
 
 /// Android-specific USB transport that uses USB Host API via JNI
 pub struct AndroidUsbTransport {
-    #[allow(dead_code)]
     device_fd: i32,
+    /// Transfers go straight to `device_fd` through usbfs rather than the Java connection
+    direct: bool,
     vendor_id: u16,  
     product_id: u16,
-    connection_handle: Option<JObject<'static>>, // Will hold UsbDeviceConnection
+    vm: Option<JavaVM>,
+    connection_handle: Option<GlobalRef>, // Holds the UsbDeviceConnection
+    interface_index: i32,
     endpoint_out: u8,
     endpoint_in: u8,
+    max_packet_size: usize,
+    /// Endpoint discovery had to guess, so a per-chip endpoint hint takes precedence
+    endpoints_ambiguous: bool,
+    /// Raw packets sent and received while packet capture is on
+    capture: Option<VecDeque<(Direction, Vec<u8>, Instant)>>,
 }
 
 impl AndroidUsbTransport {
     pub fn new(device_fd: i32, vendor_id: u16, product_id: u16) -> Self {
+        let defaults = UsbEndpoints::default();
         Self {
             device_fd,
+            direct: false,
             vendor_id,
             product_id,
+            vm: None,
             connection_handle: None,
-            endpoint_out: 0x02,  // Standard OUT endpoint for WCH ISP
-            endpoint_in: 0x82,   // Standard IN endpoint for WCH ISP  
+            interface_index: 0,
+            endpoint_out: defaults.endpoint_out,
+            endpoint_in: defaults.endpoint_in,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            endpoints_ambiguous: false,
+            capture: None,
         }
     }
 
+    /// Run `f` with a JNIEnv attached to the current thread and the stored connection
+    fn with_connection<R>(&self, f: impl FnOnce(&mut JNIEnv, &JObject) -> Result<R>) -> Result<R> {
+        let (Some(vm), Some(connection)) = (&self.vm, &self.connection_handle) else {
+            return Err(FlashError::DeviceNotFound);
+        };
+        
+        let mut env = vm.attach_current_thread()?;
+        f(&mut env, connection.as_obj())
+    }
+
     /// Initialize the USB connection using Android USB Host API via JNI
     pub fn initialize(&mut self, env: &mut JNIEnv, usb_connection: JObject) -> Result<()> {
         info!("Initializing USB transport for VID: 0x{:04X}, PID: 0x{:04X}", 
               self.vendor_id, self.product_id);
               
-        // Create global reference to USB connection for use across JNI calls
-        let global_ref = env.new_global_ref(&usb_connection)?;
+        // Keep the VM and a global reference to the USB connection so later
+        // transfers can attach to the JVM without a JNIEnv being passed in
+        self.vm = Some(env.get_java_vm()?);
+        self.connection_handle = Some(env.new_global_ref(&usb_connection)?);
         
-        // Store the connection handle 
-        // SAFETY: We convert to static lifetime for storage, but ensure proper cleanup
-        let static_ref = unsafe { std::mem::transmute(global_ref.as_obj()) };
-        self.connection_handle = Some(static_ref);
+        // Find the interface carrying the ISP endpoints
+        self.discover_endpoints(env, &usb_connection)?;
         
-        // Claim the USB interface
+        // Claim the selected USB interface
         self.claim_interface(env, &usb_connection)?;
         
-        // Discover and set endpoint addresses
-        self.discover_endpoints(env, &usb_connection)?;
-        
         info!("USB transport initialized successfully");
         Ok(())
     }
 
-    fn claim_interface(&self, env: &mut JNIEnv, connection: &JObject) -> Result<()> {
-        debug!("Claiming USB interface");
+    /// Initialize the transport on the usbfs file descriptor alone, without a Java
+    /// connection object. The descriptor stays owned by the caller and is not closed.
+    pub fn initialize_direct(&mut self) -> Result<()> {
+        info!("Initializing direct USB transport on FD {} for VID: 0x{:04X}, PID: 0x{:04X}",
+              self.device_fd, self.vendor_id, self.product_id);
+        
+        let raw = usbfs::read_descriptors(self.device_fd)?;
+        self.select_endpoints(&interfaces_from_descriptors(&raw))?;
+        usbfs::claim_interface(self.device_fd, self.interface_index as u8)?;
+        self.direct = true;
+        
+        info!("Direct USB transport initialized successfully");
+        Ok(())
+    }
+
+    /// Bind to a fresh connection after the device re-enumerated (e.g. following a
+    /// reset), re-discovering the endpoints and re-claiming the ISP interface
+    pub fn rebind(&mut self, env: &mut JNIEnv, usb_connection: JObject) -> Result<()> {
+        info!("Rebinding USB transport to a new connection");
+        
+        // The old connection died with the re-enumeration, so just drop our reference
+        self.connection_handle = None;
+        self.direct = false;
+        self.initialize(env, usb_connection)
+    }
+
+    /// Release and re-claim the ISP interface on the current connection, e.g. to
+    /// recover from transfers that failed while the device was still settling
+    pub fn reclaim(&self) -> Result<()> {
+        info!("Re-claiming USB interface {}", self.interface_index);
         
+        if let Err(e) = self.release_interface() {
+            debug!("Release before re-claim failed: {}", e);
+        }
+        
+        if self.direct {
+            return usbfs::claim_interface(self.device_fd, self.interface_index as u8);
+        }
+        self.with_connection(|env, connection| self.claim_interface(env, connection))
+    }
+
+    /// Look up a UsbInterface of the connection's device by index
+    fn interface_object<'local>(
+        env: &mut JNIEnv<'local>,
+        connection: &JObject,
+        index: i32,
+    ) -> Result<JObject<'local>> {
         // Get UsbDevice from connection
         let device = env.call_method(
             connection,
@@ -65,14 +239,19 @@ impl AndroidUsbTransport {
         )?;
         let device_obj = device.l()?;
         
-        // Get first interface (interface 0)
         let interface = env.call_method(
             &device_obj,
             "getInterface",
             "(I)Landroid/hardware/usb/UsbInterface;",
-            &[jni::objects::JValue::Int(0)]
+            &[jni::objects::JValue::Int(index)]
         )?;
-        let interface_obj = interface.l()?;
+        Ok(interface.l()?)
+    }
+
+    fn claim_interface(&self, env: &mut JNIEnv, connection: &JObject) -> Result<()> {
+        debug!("Claiming USB interface {}", self.interface_index);
+        
+        let interface_obj = Self::interface_object(env, connection, self.interface_index)?;
         
         // Claim the interface with force flag
         let claimed = env.call_method(
@@ -86,16 +265,15 @@ impl AndroidUsbTransport {
         )?;
         
         if !claimed.z()? {
-            return Err(anyhow::anyhow!("Failed to claim USB interface"));
+            return Err(FlashError::Usb(format!("Failed to claim USB interface {}", self.interface_index)));
         }
         
         debug!("USB interface claimed successfully");
         Ok(())
     }
     
-    fn discover_endpoints(&mut self, env: &mut JNIEnv, connection: &JObject) -> Result<()> {
-        debug!("Discovering USB endpoints");
-        
+    /// Describe every interface of the device along with its endpoints
+    fn enumerate_interfaces(env: &mut JNIEnv, connection: &JObject) -> Result<Vec<InterfaceInfo>> {
         // Get UsbDevice from connection
         let device = env.call_method(
             connection,
@@ -105,122 +283,471 @@ impl AndroidUsbTransport {
         )?;
         let device_obj = device.l()?;
         
-        // Get first interface (interface 0)
-        let interface = env.call_method(
-            &device_obj,
-            "getInterface",
-            "(I)Landroid/hardware/usb/UsbInterface;",
-            &[jni::objects::JValue::Int(0)]
-        )?;
-        let interface_obj = interface.l()?;
-        
-        // Get endpoint count
-        let endpoint_count = env.call_method(
-            &interface_obj,
-            "getEndpointCount",
-            "()I",
-            &[]
-        )?;
-        let count = endpoint_count.i()?;
-        
-        debug!("Found {} endpoints", count);
+        let interface_count = env.call_method(&device_obj, "getInterfaceCount", "()I", &[])?.i()?;
+        debug!("Found {} interfaces", interface_count);
         
-        for i in 0..count {
-            let endpoint = env.call_method(
-                &interface_obj,
-                "getEndpoint",
-                "(I)Landroid/hardware/usb/UsbEndpoint;",
-                &[jni::objects::JValue::Int(i)]
-            )?;
-            let endpoint_obj = endpoint.l()?;
+        let mut interfaces = Vec::with_capacity(interface_count.max(0) as usize);
+        for index in 0..interface_count {
+            let interface_obj = env.call_method(
+                &device_obj,
+                "getInterface",
+                "(I)Landroid/hardware/usb/UsbInterface;",
+                &[jni::objects::JValue::Int(index)]
+            )?.l()?;
             
-            // Get endpoint address
-            let address = env.call_method(
-                &endpoint_obj,
-                "getAddress",
-                "()I",
-                &[]
-            )?;
-            let addr = address.i()? as u8;
+            let class = env.call_method(&interface_obj, "getInterfaceClass", "()I", &[])?.i()?;
+            let endpoint_count = env.call_method(&interface_obj, "getEndpointCount", "()I", &[])?.i()?;
             
-            // Get endpoint direction
-            let direction = env.call_method(
-                &endpoint_obj,
-                "getDirection",
-                "()I", 
-                &[]
-            )?;
-            let dir = direction.i()?;
+            let mut endpoints = Vec::with_capacity(endpoint_count.max(0) as usize);
+            for i in 0..endpoint_count {
+                let endpoint_obj = env.call_method(
+                    &interface_obj,
+                    "getEndpoint",
+                    "(I)Landroid/hardware/usb/UsbEndpoint;",
+                    &[jni::objects::JValue::Int(i)]
+                )?.l()?;
+                
+                endpoints.push(EndpointInfo {
+                    address: env.call_method(&endpoint_obj, "getAddress", "()I", &[])?.i()? as u8,
+                    direction: env.call_method(&endpoint_obj, "getDirection", "()I", &[])?.i()?,
+                    endpoint_type: env.call_method(&endpoint_obj, "getType", "()I", &[])?.i()?,
+                    max_packet_size: env.call_method(&endpoint_obj, "getMaxPacketSize", "()I", &[])?.i()?,
+                });
+            }
             
-            // USB_DIR_OUT = 0, USB_DIR_IN = 128 (0x80)
-            if dir == 0 { // OUT endpoint
-                self.endpoint_out = addr;
-                debug!("Found OUT endpoint: 0x{:02X}", addr);
-            } else { // IN endpoint
-                self.endpoint_in = addr;
-                debug!("Found IN endpoint: 0x{:02X}", addr);
+            debug!("Interface {}: class=0x{:02X}, endpoints={:?}", index, class, endpoints);
+            interfaces.push(InterfaceInfo { index, class, endpoints });
+        }
+        
+        Ok(interfaces)
+    }
+    
+    fn discover_endpoints(&mut self, env: &mut JNIEnv, connection: &JObject) -> Result<()> {
+        debug!("Discovering USB endpoints");
+        
+        let interfaces = Self::enumerate_interfaces(env, connection)?;
+        self.select_endpoints(&interfaces)
+    }
+    
+    /// Use the ISP interface's endpoints, failing when no interface has a bulk pair
+    fn select_endpoints(&mut self, interfaces: &[InterfaceInfo]) -> Result<()> {
+        self.endpoints_ambiguous = discovery_ambiguous(interfaces);
+        if self.endpoints_ambiguous {
+            debug!("Endpoint discovery is ambiguous; a chip endpoint hint will take precedence");
+        }
+        
+        match select_interface(interfaces) {
+            Some(interface) => {
+                let (endpoint_out, endpoint_in) = interface.bulk_endpoints();
+                self.interface_index = interface.index;
+                self.endpoint_out = endpoint_out.unwrap_or(self.endpoint_out);
+                self.endpoint_in = endpoint_in.unwrap_or(self.endpoint_in);
+                
+                // Commands go out on the OUT endpoint, so its packet size bounds them
+                if let Some(size) = interface.endpoints
+                    .iter()
+                    .find(|ep| ep.address == self.endpoint_out)
+                    .map(|ep| ep.max_packet_size)
+                    .filter(|&size| size > 0)
+                {
+                    self.max_packet_size = size as usize;
+                }
+            }
+            None => {
+                let found = describe_interfaces(interfaces);
+                error!("No interface with bulk IN/OUT endpoints found: {}", found);
+                return Err(FlashError::NoUsableEndpoints { found });
             }
         }
         
-        debug!("Endpoint discovery completed: OUT=0x{:02X}, IN=0x{:02X}", 
-               self.endpoint_out, self.endpoint_in);
+        info!("Selected interface {}: OUT=0x{:02X}, IN=0x{:02X}, max packet {} bytes", 
+              self.interface_index, self.endpoint_out, self.endpoint_in, self.max_packet_size);
         Ok(())
     }
 
-    pub fn send_raw(&mut self, env: &mut JNIEnv, data: &[u8]) -> Result<usize> {
-        debug!("Sending {} bytes via Android USB", data.len());
+    /// Start recording every raw packet, discarding any earlier capture, or stop and drop it
+    pub fn set_packet_capture(&mut self, enabled: bool) {
+        self.capture = enabled.then(VecDeque::new);
+    }
+
+    /// Packets recorded since capture was turned on, oldest first
+    pub fn captured_packets(&self) -> Vec<CapturedPacket> {
+        let Some(capture) = &self.capture else {
+            return vec![];
+        };
+        let Some((_, _, start)) = capture.front() else {
+            return vec![];
+        };
         
-        if let Some(ref connection) = self.connection_handle {
-            // Convert data to Java byte array
-            let java_array = env.byte_array_from_slice(data)?;
+        capture
+            .iter()
+            .map(|(direction, data, at)| CapturedPacket {
+                direction: *direction,
+                offset_us: at.duration_since(*start).as_micros() as u64,
+                data: hex::encode(data),
+            })
+            .collect()
+    }
+
+    fn record_packet(&mut self, direction: Direction, data: &[u8]) {
+        if let Some(capture) = &mut self.capture {
+            if capture.len() >= MAX_CAPTURED_PACKETS {
+                capture.pop_front();
+            }
+            capture.push_back((direction, data.to_vec(), Instant::now()));
+        }
+    }
+
+    pub fn is_supported_device(vendor_id: u16, product_id: u16) -> bool {
+        SUPPORTED_USB_IDS.contains(&(vendor_id, product_id))
+    }
+    
+    pub fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    pub fn product_id(&self) -> u16 {
+        self.product_id
+    }
+
+    /// Read the manufacturer, product and serial strings of the device.
+    ///
+    /// Uses the `UsbDevice` getters when a device object is given (it may be null),
+    /// then falls back to string descriptor control transfers for anything still
+    /// missing, e.g. when `getSerialNumber` is refused without USB permission.
+    pub fn read_device_strings(&self, env: &mut JNIEnv, usb_device: &JObject) -> Result<DeviceStrings> {
+        let mut strings = DeviceStrings::default();
+        
+        if !usb_device.is_null() {
+            strings.manufacturer = Self::call_string_getter(env, usb_device, "getManufacturerName");
+            strings.product = Self::call_string_getter(env, usb_device, "getProductName");
+            strings.serial = Self::call_string_getter(env, usb_device, "getSerialNumber");
+        }
+        
+        let complete = strings.manufacturer.is_some() && strings.product.is_some() && strings.serial.is_some();
+        let Some(connection) = self.connection_handle.as_ref().filter(|_| !complete) else {
+            return Ok(strings);
+        };
+        let connection = connection.as_obj();
+        
+        let raw = env.call_method(connection, "getRawDescriptors", "()[B", &[])?.l()?;
+        if raw.is_null() {
+            debug!("Raw USB descriptors unavailable");
+            return Ok(strings);
+        }
+        let raw = env.convert_byte_array(jni::objects::JByteArray::from(raw))?;
+        let Some([manufacturer, product, serial]) = device_string_indices(&raw) else {
+            return Ok(strings);
+        };
+        
+        let lang_id = Self::get_string_descriptor(env, connection, 0, 0)?
+            .and_then(|desc| first_lang_id(&desc))
+            .unwrap_or(DEFAULT_LANG_ID);
+        
+        for (slot, index) in [
+            (&mut strings.manufacturer, manufacturer),
+            (&mut strings.product, product),
+            (&mut strings.serial, serial),
+        ] {
+            if slot.is_none() && index != 0 {
+                *slot = Self::get_string_descriptor(env, connection, index, lang_id)?
+                    .and_then(|desc| decode_string_descriptor(&desc));
+            }
+        }
+        
+        Ok(strings)
+    }
+
+    /// Call a no-argument `String` getter, treating null or an exception as unavailable
+    fn call_string_getter(env: &mut JNIEnv, object: &JObject, method: &str) -> Option<String> {
+        let value = match env.call_method(object, method, "()Ljava/lang/String;", &[]).and_then(|v| v.l()) {
+            Ok(value) => value,
+            Err(e) => {
+                // getSerialNumber throws SecurityException without USB permission on newer APIs
+                let _ = env.exception_clear();
+                debug!("{} unavailable: {}", method, e);
+                return None;
+            }
+        };
+        
+        if value.is_null() {
+            return None;
+        }
+        env.get_string(&JString::from(value)).ok().map(Into::into)
+    }
+
+    /// Fetch a raw string descriptor with a GET_DESCRIPTOR control transfer
+    fn get_string_descriptor(
+        env: &mut JNIEnv,
+        connection: &JObject,
+        index: u8,
+        lang_id: u16,
+    ) -> Result<Option<Vec<u8>>> {
+        let buffer_size = 255;
+        let java_array = env.new_byte_array(buffer_size)?;
+        
+        let result = env.call_method(
+            connection,
+            "controlTransfer",
+            "(IIII[BII)I",
+            &[
+                jni::objects::JValue::Int(USB_DIR_IN),
+                jni::objects::JValue::Int(USB_REQ_GET_DESCRIPTOR),
+                jni::objects::JValue::Int(((USB_DT_STRING as i32) << 8) | index as i32),
+                jni::objects::JValue::Int(lang_id as i32),
+                jni::objects::JValue::Object(&java_array),
+                jni::objects::JValue::Int(buffer_size),
+                jni::objects::JValue::Int(1000),
+            ],
+        )?;
+        
+        let bytes_received = result.i()?;
+        if bytes_received <= 2 {
+            debug!("String descriptor {} unavailable", index);
+            return Ok(None);
+        }
+        
+        let mut buffer = vec![0i8; bytes_received as usize];
+        env.get_byte_array_region(&java_array, 0, &mut buffer)?;
+        Ok(Some(buffer.into_iter().map(|b| b as u8).collect()))
+    }
+
+    /// Probe whether the device is still attached with a device GET_STATUS
+    /// control transfer. Never fails: a dead or closed connection reports false.
+    pub fn is_connected(&self, env: &mut JNIEnv) -> bool {
+        if self.direct {
+            let mut status = [0u8; 2];
+            return usbfs::control_transfer(
+                self.device_fd,
+                USB_DIR_IN as u8,
+                USB_REQ_GET_STATUS as u8,
+                0,
+                0,
+                &mut status,
+                Duration::from_millis(LIVENESS_TIMEOUT_MS as u64),
+            )
+            .is_ok();
+        }
+        
+        let Some(connection) = &self.connection_handle else {
+            return false;
+        };
+        
+        match Self::get_status(env, connection.as_obj()) {
+            Ok(transferred) => transferred >= 0,
+            Err(e) => {
+                // A closed connection can throw rather than return -1
+                let _ = env.exception_clear();
+                debug!("Liveness probe failed: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Issue a device GET_STATUS control transfer, returning the transferred length or -1
+    fn get_status(env: &mut JNIEnv, connection: &JObject) -> Result<i32> {
+        let status = env.new_byte_array(2)?;
+        let result = env.call_method(
+            connection,
+            "controlTransfer",
+            "(IIII[BII)I",
+            &[
+                jni::objects::JValue::Int(USB_DIR_IN),
+                jni::objects::JValue::Int(USB_REQ_GET_STATUS),
+                jni::objects::JValue::Int(0),
+                jni::objects::JValue::Int(0),
+                jni::objects::JValue::Object(&status),
+                jni::objects::JValue::Int(2),
+                jni::objects::JValue::Int(LIVENESS_TIMEOUT_MS),
+            ],
+        )?;
+        Ok(result.i()?)
+    }
+
+    pub fn release_interface(&self) -> Result<()> {
+        debug!("Releasing USB interface");
+        
+        if self.direct {
+            return usbfs::release_interface(self.device_fd, self.interface_index as u8);
+        }
+        
+        if self.connection_handle.is_none() {
+            return Ok(());
+        }
+        
+        self.with_connection(|env, connection| {
+            let interface_obj = Self::interface_object(env, connection, self.interface_index)?;
             
-            // Call bulkTransfer(endpoint, buffer, length, timeout)
-            let result = env.call_method(
+            // Release the interface
+            let released = env.call_method(
                 connection,
-                "bulkTransfer",
-                "(I[BII)I",
-                &[
-                    jni::objects::JValue::Int(self.endpoint_out as i32),
-                    jni::objects::JValue::Object(&java_array),
-                    jni::objects::JValue::Int(data.len() as i32),
-                    jni::objects::JValue::Int(5000), // 5 second timeout
-                ],
+                "releaseInterface",
+                "(Landroid/hardware/usb/UsbInterface;)Z",
+                &[jni::objects::JValue::Object(&interface_obj)]
             )?;
             
-            let bytes_sent = result.i()? as usize;
-            if bytes_sent == data.len() {
-                debug!("Successfully sent {} bytes", bytes_sent);
-                Ok(bytes_sent)
+            if released.z()? {
+                debug!("USB interface released successfully");
             } else {
-                anyhow::bail!("USB send failed: expected {}, sent {}", data.len(), bytes_sent);
+                warn!("releaseInterface returned false");
             }
-        } else {
-            anyhow::bail!("No USB connection available");
+            
+            Ok(())
+        })
+    }
+    
+    /// Release the interface and close the connection.
+    ///
+    /// Both steps are always attempted, even after the device has gone away, and
+    /// the connection is dropped regardless so the next open is not blocked by a
+    /// stale claim. The first error, if any, is returned.
+    pub fn close(&mut self) -> Result<()> {
+        info!("Closing USB transport");
+        
+        if self.direct {
+            // The descriptor belongs to the caller, so only the claim is dropped
+            let released = self.release_interface();
+            if let Err(e) = &released {
+                warn!("Failed to release USB interface: {}", e);
+            }
+            self.direct = false;
+            info!("USB transport closed");
+            return released;
+        }
+        
+        if self.connection_handle.is_none() {
+            debug!("USB transport already closed");
+            return Ok(());
         }
+        
+        let released = self.release_interface();
+        if let Err(e) = &released {
+            warn!("Failed to release USB interface: {}", e);
+        }
+        
+        let closed = self.with_connection(|env, connection| {
+            // A failed release can leave a Java exception pending, which would fail this call too
+            env.exception_clear()?;
+            env.call_method(connection, "close", "()V", &[])?;
+            Ok(())
+        });
+        match &closed {
+            Ok(()) => debug!("USB connection closed"),
+            Err(e) => warn!("Failed to close USB connection: {}", e),
+        }
+        
+        self.connection_handle = None;
+        info!("USB transport closed");
+        released.and(closed)
+    }
+}
+
+impl Transport for AndroidUsbTransport {
+    fn send_raw(&mut self, data: &[u8], timeout: Duration) -> Result<usize> {
+        let bytes_sent = self.send_bulk(data, timeout)?;
+        self.record_packet(Direction::Out, &data[..bytes_sent]);
+        Ok(bytes_sent)
+    }
+
+    fn recv_raw(&mut self, buffer_size: usize, timeout: Duration) -> Result<Vec<u8>> {
+        let received = self.recv_bulk(buffer_size, timeout)?;
+        self.record_packet(Direction::In, &received);
+        Ok(received)
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
     }
 
-    pub fn recv_raw(&mut self, env: &mut JNIEnv, timeout: Duration) -> Result<Vec<u8>> {
-        debug!("Receiving data via Android USB with timeout: {:?}", timeout);
+    fn endpoints(&self) -> Option<(u8, u8)> {
+        (self.direct || self.connection_handle.is_some()).then_some((self.endpoint_out, self.endpoint_in))
+    }
+
+    fn apply_endpoint_hint(&mut self, (endpoint_out, endpoint_in): (u8, u8)) {
+        if !self.endpoints_ambiguous || (endpoint_out, endpoint_in) == (self.endpoint_out, self.endpoint_in) {
+            return;
+        }
         
-        if let Some(ref connection) = self.connection_handle {
-            // Create receive buffer (standard WCH ISP packet size)
-            let buffer_size = 64;
+        info!("Using chip endpoint hint OUT 0x{:02x}/IN 0x{:02x} instead of discovered 0x{:02x}/0x{:02x}",
+              endpoint_out, endpoint_in, self.endpoint_out, self.endpoint_in);
+        self.endpoint_out = endpoint_out;
+        self.endpoint_in = endpoint_in;
+    }
+}
+
+impl AndroidUsbTransport {
+    fn send_bulk(&mut self, data: &[u8], timeout: Duration) -> Result<usize> {
+        debug!("Sending {} bytes via Android USB", data.len());
+        
+        if self.direct {
+            let bytes_sent = send_all(data, timeout, |tail, remaining| {
+                let mut tail = tail.to_vec();
+                Ok(usbfs::bulk_transfer(self.device_fd, self.endpoint_out, &mut tail, remaining)? as i32)
+            })?;
+            debug!("Successfully sent {} bytes", bytes_sent);
+            return Ok(bytes_sent);
+        }
+        
+        self.with_connection(|env, connection| {
+            let bytes_sent = send_all(data, timeout, |tail, remaining| {
+                // Convert the unsent tail to a Java byte array
+                let java_array = env.byte_array_from_slice(tail)?;
+                
+                // Call bulkTransfer(endpoint, buffer, length, timeout)
+                detect_permission_loss(remaining, || {
+                    let result = env.call_method(
+                        connection,
+                        "bulkTransfer",
+                        "(I[BII)I",
+                        &[
+                            jni::objects::JValue::Int(self.endpoint_out as i32),
+                            jni::objects::JValue::Object(&java_array),
+                            jni::objects::JValue::Int(tail.len() as i32),
+                            jni::objects::JValue::Int(remaining.as_millis() as i32),
+                        ],
+                    )?;
+                    Ok(result.i()?)
+                })
+            })?;
+            
+            debug!("Successfully sent {} bytes", bytes_sent);
+            Ok(bytes_sent)
+        })
+    }
+
+    fn recv_bulk(&mut self, buffer_size: usize, timeout: Duration) -> Result<Vec<u8>> {
+        debug!("Receiving up to {} bytes via Android USB with timeout: {:?}", buffer_size, timeout);
+        
+        if self.direct {
+            let mut buffer = vec![0u8; buffer_size];
+            let bytes_received = usbfs::bulk_transfer(self.device_fd, self.endpoint_in, &mut buffer, timeout)?;
+            if bytes_received == 0 {
+                return Err(FlashError::UsbTimeout("USB receive failed or timeout".to_string()));
+            }
+            buffer.truncate(bytes_received);
+            debug!("Received {} bytes", bytes_received);
+            return Ok(buffer);
+        }
+        
+        self.with_connection(|env, connection| {
+            let buffer_size = buffer_size as i32;
             let java_array = env.new_byte_array(buffer_size)?;
             
             // Call bulkTransfer for receive
-            let result = env.call_method(
-                connection,
-                "bulkTransfer",
-                "(I[BII)I",
-                &[
-                    jni::objects::JValue::Int(self.endpoint_in as i32),
-                    jni::objects::JValue::Object(&java_array),
-                    jni::objects::JValue::Int(buffer_size as i32),
-                    jni::objects::JValue::Int(timeout.as_millis() as i32),
-                ],
-            )?;
-            
-            let bytes_received = result.i()?;
+            let bytes_received = detect_permission_loss(timeout, || {
+                let result = env.call_method(
+                    connection,
+                    "bulkTransfer",
+                    "(I[BII)I",
+                    &[
+                        jni::objects::JValue::Int(self.endpoint_in as i32),
+                        jni::objects::JValue::Object(&java_array),
+                        jni::objects::JValue::Int(buffer_size),
+                        jni::objects::JValue::Int(timeout.as_millis() as i32),
+                    ],
+                )?;
+                Ok(result.i()?)
+            })?;
             if bytes_received > 0 {
                 let mut buffer = vec![0i8; bytes_received as usize];
                 env.get_byte_array_region(&java_array, 0, &mut buffer)?;
@@ -229,78 +756,305 @@ impl AndroidUsbTransport {
                 debug!("Received {} bytes", bytes_received);
                 Ok(result)
             } else {
-                anyhow::bail!("USB receive failed or timeout");
+                Err(FlashError::UsbTimeout("USB receive failed or timeout".to_string()))
             }
-        } else {
-            anyhow::bail!("No USB connection available");
+        })
+    }
+}
+
+/// Transport behind a device handle: the USB link, or a simulated bootloader
+/// when built with the `simulator` feature
+pub enum DeviceTransport {
+    Usb(AndroidUsbTransport),
+    #[cfg(feature = "simulator")]
+    Simulated(crate::simulator::SimulatedTransport),
+}
+
+impl DeviceTransport {
+    /// The USB link, for operations that need the Android USB Host API
+    pub fn usb(&self) -> Result<&AndroidUsbTransport> {
+        match self {
+            DeviceTransport::Usb(usb) => Ok(usb),
+            #[cfg(feature = "simulator")]
+            DeviceTransport::Simulated(_) => Err(FlashError::InvalidArgument("Not available on a simulated device".to_string())),
         }
     }
 
-    pub fn is_supported_device(vendor_id: u16, product_id: u16) -> bool {
-        matches!((vendor_id, product_id), (0x4348, 0x55e0) | (0x1a86, 0x55e0))
+    /// Mutable access to the USB link
+    pub fn usb_mut(&mut self) -> Result<&mut AndroidUsbTransport> {
+        match self {
+            DeviceTransport::Usb(usb) => Ok(usb),
+            #[cfg(feature = "simulator")]
+            DeviceTransport::Simulated(_) => Err(FlashError::InvalidArgument("Not available on a simulated device".to_string())),
+        }
+    }
+}
+
+impl Transport for DeviceTransport {
+    fn send_raw(&mut self, data: &[u8], timeout: Duration) -> Result<usize> {
+        match self {
+            DeviceTransport::Usb(usb) => usb.send_raw(data, timeout),
+            #[cfg(feature = "simulator")]
+            DeviceTransport::Simulated(sim) => sim.send_raw(data, timeout),
+        }
+    }
+
+    fn recv_raw(&mut self, buffer_size: usize, timeout: Duration) -> Result<Vec<u8>> {
+        match self {
+            DeviceTransport::Usb(usb) => usb.recv_raw(buffer_size, timeout),
+            #[cfg(feature = "simulator")]
+            DeviceTransport::Simulated(sim) => sim.recv_raw(buffer_size, timeout),
+        }
+    }
+
+    fn max_packet_size(&self) -> usize {
+        match self {
+            DeviceTransport::Usb(usb) => usb.max_packet_size(),
+            #[cfg(feature = "simulator")]
+            DeviceTransport::Simulated(sim) => sim.max_packet_size(),
+        }
+    }
+
+    fn endpoints(&self) -> Option<(u8, u8)> {
+        match self {
+            DeviceTransport::Usb(usb) => usb.endpoints(),
+            #[cfg(feature = "simulator")]
+            DeviceTransport::Simulated(sim) => sim.endpoints(),
+        }
+    }
+
+    fn apply_endpoint_hint(&mut self, endpoints: (u8, u8)) {
+        match self {
+            DeviceTransport::Usb(usb) => usb.apply_endpoint_hint(endpoints),
+            #[cfg(feature = "simulator")]
+            DeviceTransport::Simulated(sim) => sim.apply_endpoint_hint(endpoints),
+        }
+    }
+}
+
+/// Endpoint description gathered during interface discovery
+#[derive(Debug, Clone)]
+pub struct EndpointInfo {
+    pub address: u8,
+    pub direction: i32,
+    pub endpoint_type: i32,
+    pub max_packet_size: i32,
+}
+
+/// Interface description gathered during interface discovery
+#[derive(Debug, Clone)]
+pub struct InterfaceInfo {
+    pub index: i32,
+    pub class: i32,
+    pub endpoints: Vec<EndpointInfo>,
+}
+
+impl InterfaceInfo {
+    /// First bulk OUT and bulk IN endpoint addresses of this interface
+    pub fn bulk_endpoints(&self) -> (Option<u8>, Option<u8>) {
+        let bulk = |direction_in: bool| {
+            self.endpoints
+                .iter()
+                .find(|ep| ep.endpoint_type == USB_ENDPOINT_XFER_BULK && (ep.direction == USB_DIR_IN) == direction_in)
+                .map(|ep| ep.address)
+        };
+        (bulk(false), bulk(true))
     }
+}
+
+/// Pick the interface carrying the ISP endpoints: the first with both a bulk IN
+/// and a bulk OUT endpoint, preferring vendor-specific interfaces over e.g. the
+/// CDC data interface of CH340 composite devices
+fn select_interface(interfaces: &[InterfaceInfo]) -> Option<&InterfaceInfo> {
+    let usable = |iface: &&InterfaceInfo| matches!(iface.bulk_endpoints(), (Some(_), Some(_)));
     
-    pub fn release_interface(&self, env: &mut JNIEnv) -> Result<()> {
-        debug!("Releasing USB interface");
-        
-        if let Some(ref connection) = self.connection_handle {
-            // Get UsbDevice from connection
-            let device = env.call_method(
-                connection,
-                "getDevice",
-                "()Landroid/hardware/usb/UsbDevice;",
-                &[]
-            )?;
-            let device_obj = device.l()?;
-            
-            // Get first interface (interface 0)
-            let interface = env.call_method(
-                &device_obj,
-                "getInterface",
-                "(I)Landroid/hardware/usb/UsbInterface;",
-                &[jni::objects::JValue::Int(0)]
-            )?;
-            let interface_obj = interface.l()?;
-            
-            // Release the interface
-            let released = env.call_method(
-                connection,
-                "releaseInterface",
-                "(Landroid/hardware/usb/UsbInterface;)Z",
-                &[jni::objects::JValue::Object(&interface_obj)]
-            )?;
-            
-            if released.z()? {
-                debug!("USB interface released successfully");
+    interfaces
+        .iter()
+        .filter(usable)
+        .find(|iface| iface.class == USB_CLASS_VENDOR_SPEC)
+        .or_else(|| interfaces.iter().find(usable))
+}
+
+/// Describe interfaces and their endpoints for diagnostics, e.g.
+/// "interface 0 (class 0xff): 0x81 interrupt IN, 0x02 bulk OUT"
+fn describe_interfaces(interfaces: &[InterfaceInfo]) -> String {
+    if interfaces.is_empty() {
+        return "no interfaces".to_string();
+    }
+    
+    interfaces
+        .iter()
+        .map(|iface| {
+            let endpoints = if iface.endpoints.is_empty() {
+                "no endpoints".to_string()
             } else {
-                debug!("Warning: Failed to release USB interface");
-            }
+                iface.endpoints
+                    .iter()
+                    .map(|ep| {
+                        let kind = match ep.endpoint_type {
+                            0 => "control",
+                            1 => "isochronous",
+                            USB_ENDPOINT_XFER_BULK => "bulk",
+                            _ => "interrupt",
+                        };
+                        let direction = if ep.direction == USB_DIR_IN { "IN" } else { "OUT" };
+                        format!("0x{:02x} {} {}", ep.address, kind, direction)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            format!("interface {} (class 0x{:02x}): {}", iface.index, iface.class, endpoints)
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Whether endpoint discovery had to guess: no single preferred interface with a
+/// bulk pair, or the chosen one offering several bulk endpoints in a direction
+fn discovery_ambiguous(interfaces: &[InterfaceInfo]) -> bool {
+    let usable: Vec<&InterfaceInfo> = interfaces
+        .iter()
+        .filter(|iface| matches!(iface.bulk_endpoints(), (Some(_), Some(_))))
+        .collect();
+    let vendor: Vec<&InterfaceInfo> = usable.iter().copied().filter(|iface| iface.class == USB_CLASS_VENDOR_SPEC).collect();
+    let candidates = if vendor.is_empty() { usable } else { vendor };
+    
+    match candidates.as_slice() {
+        [interface] => [false, true].iter().any(|&direction_in| {
+            interface.endpoints
+                .iter()
+                .filter(|ep| ep.endpoint_type == USB_ENDPOINT_XFER_BULK && (ep.direction == USB_DIR_IN) == direction_in)
+                .count() > 1
+        }),
+        _ => true,
+    }
+}
+
+/// Send all of `data`, re-sending the unsent tail after a partial transfer until
+/// everything is out, `send` reports an error (a negative count or no progress),
+/// or `timeout` elapses. Returns the total number of bytes sent.
+fn send_all<F>(data: &[u8], timeout: Duration, mut send: F) -> Result<usize>
+where
+    F: FnMut(&[u8], Duration) -> Result<i32>,
+{
+    let deadline = Instant::now() + timeout;
+    let mut offset = 0;
+    
+    while offset < data.len() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(FlashError::UsbTimeout(format!("Send timed out after {} of {} bytes", offset, data.len())));
         }
         
-        Ok(())
+        let sent = send(&data[offset..], remaining)?;
+        if sent <= 0 {
+            return Err(FlashError::Usb(format!("Send failed after {} of {} bytes", offset, data.len())));
+        }
+        
+        offset += (sent as usize).min(data.len() - offset);
+        if offset < data.len() {
+            debug!("Partial send: {} of {} bytes", offset, data.len());
+        }
     }
     
-    pub fn close(&mut self, env: &mut JNIEnv) -> Result<()> {
-        info!("Closing USB transport");
-        
-        // Release interface before closing
-        self.release_interface(env)?;
+    Ok(offset)
+}
+
+/// Run a `bulkTransfer` call, telling revoked USB permission apart from a timeout.
+///
+/// A timed-out transfer returns -1 only after waiting, while one on a connection
+/// whose permission was revoked returns -1 at once, every time. An immediate
+/// failure is retried, nothing having been transferred, and
+/// `PERMISSION_LOST_FAILURES` of them in a row give `PermissionLost`.
+fn detect_permission_loss<F>(timeout: Duration, mut transfer: F) -> Result<i32>
+where
+    F: FnMut() -> Result<i32>,
+{
+    for attempt in 1..=PERMISSION_LOST_FAILURES {
+        let started = Instant::now();
+        let result = transfer()?;
+        if result >= 0 || timeout <= IMMEDIATE_FAILURE_WINDOW || started.elapsed() >= IMMEDIATE_FAILURE_WINDOW {
+            return Ok(result);
+        }
+        debug!("Bulk transfer failed immediately ({}/{})", attempt, PERMISSION_LOST_FAILURES);
+    }
+    
+    warn!("Bulk transfers keep failing immediately; USB permission was likely revoked");
+    Err(FlashError::PermissionLost)
+}
+
+/// Extract the (iManufacturer, iProduct, iSerialNumber) string indices from raw
+/// descriptors, which start with the 18-byte device descriptor
+fn device_string_indices(raw: &[u8]) -> Option<[u8; 3]> {
+    if raw.len() < 18 || raw[1] != USB_DT_DEVICE {
+        return None;
+    }
+    Some([raw[14], raw[15], raw[16]])
+}
+
+/// Describe the interfaces in raw configuration descriptors, as read from a usbfs
+/// device file. Only the default alternate setting of each interface is listed.
+fn interfaces_from_descriptors(raw: &[u8]) -> Vec<InterfaceInfo> {
+    let mut interfaces: Vec<InterfaceInfo> = Vec::new();
+    let mut in_default_setting = false;
+    let mut offset = 0;
+    
+    while offset + 2 <= raw.len() {
+        let len = raw[offset] as usize;
+        if len < 2 || offset + len > raw.len() {
+            break;
+        }
+        let desc = &raw[offset..offset + len];
         
-        // Close the USB connection
-        if let Some(ref connection) = self.connection_handle {
-            let _result = env.call_method(
-                connection,
-                "close",
-                "()V",
-                &[]
-            );
-            // Note: We don't fail if close() fails as connection may already be closed
+        match desc[1] {
+            USB_DT_INTERFACE if len >= 9 => {
+                in_default_setting = desc[3] == 0;
+                if in_default_setting {
+                    interfaces.push(InterfaceInfo { index: desc[2] as i32, class: desc[5] as i32, endpoints: vec![] });
+                }
+            }
+            USB_DT_ENDPOINT if len >= 7 && in_default_setting => {
+                if let Some(interface) = interfaces.last_mut() {
+                    interface.endpoints.push(EndpointInfo {
+                        address: desc[2],
+                        direction: (desc[2] & USB_DIR_IN as u8) as i32,
+                        endpoint_type: (desc[3] & 0x03) as i32,
+                        max_packet_size: (u16::from_le_bytes([desc[4], desc[5]]) & 0x7ff) as i32,
+                    });
+                }
+            }
+            _ => {}
         }
         
-        self.connection_handle = None;
-        info!("USB transport closed");
-        Ok(())
+        offset += len;
+    }
+    
+    interfaces
+}
+
+/// First language ID listed in string descriptor 0
+fn first_lang_id(desc: &[u8]) -> Option<u16> {
+    if desc.len() < 4 || desc[1] != USB_DT_STRING {
+        return None;
     }
+    Some(u16::from_le_bytes([desc[2], desc[3]]))
+}
+
+/// Decode a UTF-16LE string descriptor, returning None when it is malformed or empty
+fn decode_string_descriptor(desc: &[u8]) -> Option<String> {
+    if desc.len() < 2 || desc[1] != USB_DT_STRING {
+        return None;
+    }
+    
+    let len = (desc[0] as usize).min(desc.len());
+    let units: Vec<u16> = desc[2..len.max(2)]
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    
+    let text = String::from_utf16_lossy(&units);
+    let text = text.trim_end_matches('\0');
+    (!text.is_empty()).then(|| text.to_string())
 }
 
 /// USB endpoint configuration for WCH ISP devices
@@ -312,8 +1066,271 @@ pub struct UsbEndpoints {
 impl Default for UsbEndpoints {
     fn default() -> Self {
         Self {
-            endpoint_out: 0x02,
-            endpoint_in: 0x82,
+            endpoint_out: ISP_ENDPOINT_OUT,
+            endpoint_in: ISP_ENDPOINT_IN,
+        }
+    }
+}
+
+/// Transport that records sent packets and plays back scripted responses
+#[cfg(test)]
+pub(crate) struct MockTransport {
+    pub responses: std::collections::VecDeque<Vec<u8>>,
+    pub sent: Vec<Vec<u8>>,
+    pub max_packet_size: usize,
+}
+
+#[cfg(test)]
+impl MockTransport {
+    pub fn new(responses: Vec<Vec<u8>>) -> Self {
+        Self {
+            responses: responses.into(),
+            sent: vec![],
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
         }
     }
-}
\ No newline at end of file
+
+    /// Build a raw response packet: type, status, 16-bit payload length, payload
+    pub fn response(cmd_type: u8, status: u8, payload: &[u8]) -> Vec<u8> {
+        let mut raw = vec![cmd_type, status];
+        raw.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        raw.extend_from_slice(payload);
+        raw
+    }
+}
+
+#[cfg(test)]
+impl Transport for MockTransport {
+    fn send_raw(&mut self, data: &[u8], _timeout: Duration) -> Result<usize> {
+        self.sent.push(data.to_vec());
+        Ok(data.len())
+    }
+
+    fn recv_raw(&mut self, _buffer_size: usize, _timeout: Duration) -> Result<Vec<u8>> {
+        self.responses
+            .pop_front()
+            .ok_or_else(|| FlashError::UsbTimeout("USB receive failed or timeout".to_string()))
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    fn endpoints(&self) -> Option<(u8, u8)> {
+        Some((0x02, 0x82))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bulk(address: u8) -> EndpointInfo {
+        EndpointInfo {
+            address,
+            direction: if address & 0x80 != 0 { USB_DIR_IN } else { 0 },
+            endpoint_type: USB_ENDPOINT_XFER_BULK,
+            max_packet_size: 64,
+        }
+    }
+
+    fn interrupt(address: u8) -> EndpointInfo {
+        EndpointInfo { endpoint_type: 3, ..bulk(address) }
+    }
+
+    #[test]
+    fn test_select_single_interface() {
+        let interfaces = vec![InterfaceInfo { index: 0, class: 0xff, endpoints: vec![bulk(0x82), bulk(0x02)] }];
+        let selected = select_interface(&interfaces).unwrap();
+        assert_eq!(selected.index, 0);
+        assert_eq!(selected.bulk_endpoints(), (Some(0x02), Some(0x82)));
+    }
+
+    #[test]
+    fn test_select_isp_interface_on_composite_device() {
+        let interfaces = vec![
+            InterfaceInfo { index: 0, class: 0x02, endpoints: vec![interrupt(0x83)] },
+            InterfaceInfo { index: 1, class: 0x0a, endpoints: vec![bulk(0x81), bulk(0x01)] },
+            InterfaceInfo { index: 2, class: 0xff, endpoints: vec![bulk(0x84), bulk(0x04)] },
+        ];
+        let selected = select_interface(&interfaces).unwrap();
+        assert_eq!(selected.index, 2);
+        assert_eq!(selected.bulk_endpoints(), (Some(0x04), Some(0x84)));
+        
+        // Without a vendor interface the first bulk pair wins
+        let selected = select_interface(&interfaces[..2]).unwrap();
+        assert_eq!(selected.index, 1);
+    }
+
+    #[test]
+    fn test_select_interface_without_bulk_pair() {
+        let interfaces = vec![
+            InterfaceInfo { index: 0, class: 0xff, endpoints: vec![interrupt(0x81), bulk(0x02)] },
+        ];
+        assert!(select_interface(&interfaces).is_none());
+        
+        // Discovery fails early, naming what it found
+        let mut transport = AndroidUsbTransport::new(-1, 0x4348, 0x55e0);
+        match transport.select_endpoints(&interfaces) {
+            Err(FlashError::NoUsableEndpoints { found }) => {
+                assert_eq!(found, "interface 0 (class 0xff): 0x81 interrupt IN, 0x02 bulk OUT");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(describe_interfaces(&[]), "no interfaces");
+    }
+
+    #[test]
+    fn test_discovery_ambiguous() {
+        let single = vec![InterfaceInfo { index: 0, class: 0xff, endpoints: vec![bulk(0x82), bulk(0x02)] }];
+        assert!(!discovery_ambiguous(&single));
+        
+        // A vendor interface settles a composite device
+        let composite = vec![
+            InterfaceInfo { index: 0, class: 0x0a, endpoints: vec![bulk(0x81), bulk(0x01)] },
+            InterfaceInfo { index: 1, class: 0xff, endpoints: vec![bulk(0x82), bulk(0x02)] },
+        ];
+        assert!(!discovery_ambiguous(&composite));
+        
+        // Two candidates without a vendor interface, several bulk pairs, or none
+        assert!(discovery_ambiguous(&composite.iter().cloned().map(|iface| InterfaceInfo { class: 0x0a, ..iface }).collect::<Vec<_>>()));
+        assert!(discovery_ambiguous(&[InterfaceInfo { index: 0, class: 0xff, endpoints: vec![bulk(0x81), bulk(0x01), bulk(0x82), bulk(0x02)] }]));
+        assert!(discovery_ambiguous(&[]));
+    }
+
+    #[test]
+    fn test_apply_endpoint_hint() {
+        let mut transport = AndroidUsbTransport::new(-1, 0x4348, 0x55e0);
+        transport.select_endpoints(&[InterfaceInfo { index: 0, class: 0xff, endpoints: vec![bulk(0x81), bulk(0x01)] }]).unwrap();
+        transport.apply_endpoint_hint((ISP_ENDPOINT_OUT, ISP_ENDPOINT_IN));
+        assert_eq!((transport.endpoint_out, transport.endpoint_in), (0x01, 0x81));
+        
+        // Descriptors listing two bulk pairs leave the choice to the hint
+        transport.select_endpoints(&[InterfaceInfo { index: 0, class: 0xff, endpoints: vec![bulk(0x81), bulk(0x01), bulk(0x82), bulk(0x02)] }]).unwrap();
+        assert_eq!((transport.endpoint_out, transport.endpoint_in), (0x01, 0x81));
+        transport.apply_endpoint_hint((ISP_ENDPOINT_OUT, ISP_ENDPOINT_IN));
+        assert_eq!((transport.endpoint_out, transport.endpoint_in), (ISP_ENDPOINT_OUT, ISP_ENDPOINT_IN));
+    }
+
+    #[test]
+    fn test_send_all_resends_tail() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut chunks = vec![];
+        let sent = send_all(&data, Duration::from_millis(100), |tail, _| {
+            chunks.push(tail.to_vec());
+            Ok(tail.len().min(40) as i32)
+        }).unwrap();
+        
+        assert_eq!(sent, 100);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[1], data[40..].to_vec());
+        assert_eq!(chunks[2], data[80..].to_vec());
+    }
+
+    #[test]
+    fn test_send_all_fails_without_progress() {
+        let mut calls = 0;
+        let result = send_all(&[0u8; 64], Duration::from_millis(100), |_, _| {
+            calls += 1;
+            Ok(if calls == 1 { 32 } else { -1 })
+        });
+        assert!(matches!(result, Err(FlashError::Usb(_))));
+    }
+
+    #[test]
+    fn test_detect_permission_loss() {
+        let timeout = Duration::from_millis(1000);
+        
+        let mut calls = 0;
+        let result = detect_permission_loss(timeout, || { calls += 1; Ok(-1) });
+        assert!(matches!(result, Err(FlashError::PermissionLost)));
+        assert_eq!(calls, PERMISSION_LOST_FAILURES);
+        
+        // A transient immediate failure is retried
+        let mut calls = 0;
+        let result = detect_permission_loss(timeout, || { calls += 1; Ok(if calls == 1 { -1 } else { 64 }) });
+        assert_eq!(result.unwrap(), 64);
+        
+        // With a timeout this short an immediate -1 is just a timeout
+        let result = detect_permission_loss(Duration::from_millis(5), || Ok(-1));
+        assert_eq!(result.unwrap(), -1);
+    }
+
+    #[test]
+    fn test_packet_capture() {
+        let mut transport = AndroidUsbTransport::new(-1, 0x4348, 0x55e0);
+        transport.record_packet(Direction::Out, &[0xa1]);
+        assert!(transport.captured_packets().is_empty());
+        
+        transport.set_packet_capture(true);
+        transport.record_packet(Direction::Out, &[0xa1, 0x12, 0x00]);
+        transport.record_packet(Direction::In, &[0xa1, 0x00]);
+        let packets = transport.captured_packets();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0], CapturedPacket { direction: Direction::Out, offset_us: 0, data: "a11200".to_string() });
+        assert_eq!(packets[1].direction, Direction::In);
+        assert_eq!(packets[1].data, "a100");
+        assert_eq!(
+            serde_json::to_value(&packets[0]).unwrap(),
+            serde_json::json!({"direction": "out", "offsetUs": 0, "data": "a11200"}),
+        );
+        
+        // The buffer keeps only the newest packets
+        for i in 0..MAX_CAPTURED_PACKETS {
+            transport.record_packet(Direction::Out, &[i as u8]);
+        }
+        let packets = transport.captured_packets();
+        assert_eq!(packets.len(), MAX_CAPTURED_PACKETS);
+        assert_eq!(packets[0].data, "00");
+        
+        transport.set_packet_capture(false);
+        assert!(transport.captured_packets().is_empty());
+    }
+
+    #[test]
+    fn test_device_string_indices() {
+        let mut raw = vec![0x12, 0x01, 0x10, 0x01, 0xff, 0x80, 0x55, 0x40,
+                           0x48, 0x43, 0xe0, 0x55, 0x00, 0x02, 0x01, 0x02, 0x03, 0x01];
+        assert_eq!(device_string_indices(&raw), Some([1, 2, 3]));
+        
+        raw[1] = 0x02;
+        assert_eq!(device_string_indices(&raw), None);
+        assert_eq!(device_string_indices(&raw[..10]), None);
+    }
+
+    #[test]
+    fn test_interfaces_from_descriptors() {
+        let raw = [
+            // Device and configuration descriptors
+            0x12, 0x01, 0x10, 0x01, 0xff, 0x80, 0x55, 0x40, 0x48, 0x43, 0xe0, 0x55, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01,
+            0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+            // Vendor-specific interface 0 with bulk OUT 0x02 and IN 0x82
+            0x09, 0x04, 0x00, 0x00, 0x02, 0xff, 0x80, 0x55, 0x00,
+            0x07, 0x05, 0x82, 0x02, 0x40, 0x00, 0x00,
+            0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00,
+            // Alternate setting 1, ignored
+            0x09, 0x04, 0x00, 0x01, 0x01, 0xff, 0x00, 0x00, 0x00,
+            0x07, 0x05, 0x01, 0x02, 0x08, 0x00, 0x00,
+        ];
+        
+        let interfaces = interfaces_from_descriptors(&raw);
+        assert_eq!(interfaces.len(), 1);
+        assert_eq!(interfaces[0].class, USB_CLASS_VENDOR_SPEC);
+        assert_eq!(interfaces[0].endpoints.len(), 2);
+        assert_eq!(interfaces[0].bulk_endpoints(), (Some(0x02), Some(0x82)));
+        assert_eq!(interfaces[0].endpoints[0].max_packet_size, 64);
+        
+        // Truncated descriptors stop parsing rather than panicking
+        assert_eq!(interfaces_from_descriptors(&raw[..30]).len(), 0);
+    }
+
+    #[test]
+    fn test_decode_string_descriptor() {
+        let desc = [0x0a, 0x03, b'U', 0, b'S', 0, b'B', 0, b'1', 0, 0xaa, 0xbb];
+        assert_eq!(decode_string_descriptor(&desc), Some("USB1".to_string()));
+        assert_eq!(first_lang_id(&[0x04, 0x03, 0x09, 0x04]), Some(0x0409));
+        
+        assert_eq!(decode_string_descriptor(&[0x02, 0x03]), None);
+        assert_eq!(decode_string_descriptor(&[0x04, 0x01, b'A', 0]), None);
+    }
+}
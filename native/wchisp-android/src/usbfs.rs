@@ -0,0 +1,152 @@
+//! Direct USBFS access
+//!
+//! This module talks to a USB device through the kernel's usbdevfs ioctls on the
+//! file descriptor from `UsbDeviceConnection.getFileDescriptor()`, so transfers
+//! need neither JNI calls nor the Java connection object.
+
+use crate::error::{FlashError, Result};
+use std::os::raw::{c_uint, c_void};
+use std::time::Duration;
+
+/// Largest descriptor set read from the device file
+const MAX_DESCRIPTORS_SIZE: usize = 4096;
+
+/// `struct usbdevfs_bulktransfer` from linux/usbdevice_fs.h
+#[repr(C)]
+struct BulkTransfer {
+    ep: c_uint,
+    len: c_uint,
+    timeout: c_uint,
+    data: *mut c_void,
+}
+
+/// `struct usbdevfs_ctrltransfer` from linux/usbdevice_fs.h
+#[repr(C)]
+struct CtrlTransfer {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    timeout: u32,
+    data: *mut c_void,
+}
+
+/// Encode an ioctl request number the way the kernel's _IOC macro does
+const fn ioc(dir: u32, nr: u32, size: usize) -> u32 {
+    (dir << 30) | ((size as u32) << 16) | ((b'U' as u32) << 8) | nr
+}
+
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+/// USBDEVFS_CONTROL: _IOWR('U', 0, struct usbdevfs_ctrltransfer)
+const USBDEVFS_CONTROL: u32 = ioc(IOC_READ | IOC_WRITE, 0, std::mem::size_of::<CtrlTransfer>());
+
+/// USBDEVFS_BULK: _IOWR('U', 2, struct usbdevfs_bulktransfer)
+const USBDEVFS_BULK: u32 = ioc(IOC_READ | IOC_WRITE, 2, std::mem::size_of::<BulkTransfer>());
+
+/// USBDEVFS_CLAIMINTERFACE: _IOR('U', 15, unsigned int)
+const USBDEVFS_CLAIMINTERFACE: u32 = ioc(IOC_READ, 15, std::mem::size_of::<c_uint>());
+
+/// USBDEVFS_RELEASEINTERFACE: _IOR('U', 16, unsigned int)
+const USBDEVFS_RELEASEINTERFACE: u32 = ioc(IOC_READ, 16, std::mem::size_of::<c_uint>());
+
+fn ioctl(fd: i32, request: u32, arg: *mut c_void) -> std::io::Result<i32> {
+    // SAFETY: every request used here takes a pointer to a live value of the
+    // type encoded in the request number
+    let result = unsafe { libc::ioctl(fd, request as _, arg) };
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// Read the raw device and configuration descriptors from the device file
+pub fn read_descriptors(fd: i32) -> Result<Vec<u8>> {
+    let mut buffer = vec![0u8; MAX_DESCRIPTORS_SIZE];
+    // SAFETY: the buffer is valid for writes of its full length
+    let read = unsafe { libc::pread(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len(), 0) };
+    if read < 0 {
+        return Err(FlashError::Usb(format!("Failed to read USB descriptors: {}", std::io::Error::last_os_error())));
+    }
+    buffer.truncate(read as usize);
+    Ok(buffer)
+}
+
+pub fn claim_interface(fd: i32, interface: u8) -> Result<()> {
+    let mut number = interface as c_uint;
+    ioctl(fd, USBDEVFS_CLAIMINTERFACE, &mut number as *mut c_uint as *mut c_void)
+        .map_err(|e| FlashError::Usb(format!("Failed to claim USB interface {}: {}", interface, e)))?;
+    Ok(())
+}
+
+pub fn release_interface(fd: i32, interface: u8) -> Result<()> {
+    let mut number = interface as c_uint;
+    ioctl(fd, USBDEVFS_RELEASEINTERFACE, &mut number as *mut c_uint as *mut c_void)
+        .map_err(|e| FlashError::Usb(format!("Failed to release USB interface {}: {}", interface, e)))?;
+    Ok(())
+}
+
+/// Run one control transfer on endpoint 0, reading into or writing from `data`
+/// depending on the direction bit of `request_type`. Returns the number of bytes transferred.
+pub fn control_transfer(
+    fd: i32,
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    data: &mut [u8],
+    timeout: Duration,
+) -> Result<usize> {
+    let mut transfer = CtrlTransfer {
+        request_type,
+        request,
+        value,
+        index,
+        length: data.len().min(u16::MAX as usize) as u16,
+        timeout: timeout.as_millis().min(u32::MAX as u128) as u32,
+        data: data.as_mut_ptr() as *mut c_void,
+    };
+
+    ioctl(fd, USBDEVFS_CONTROL, &mut transfer as *mut CtrlTransfer as *mut c_void)
+        .map(|count| count as usize)
+        .map_err(|e| FlashError::Usb(format!("Control transfer 0x{:02x} failed: {}", request, e)))
+}
+
+/// Run one bulk transfer on `endpoint`, whose direction bit selects reading into
+/// or writing from `data`. Returns the number of bytes transferred.
+pub fn bulk_transfer(fd: i32, endpoint: u8, data: &mut [u8], timeout: Duration) -> Result<usize> {
+    let mut transfer = BulkTransfer {
+        ep: endpoint as c_uint,
+        len: data.len() as c_uint,
+        timeout: timeout.as_millis().min(c_uint::MAX as u128) as c_uint,
+        data: data.as_mut_ptr() as *mut c_void,
+    };
+
+    match ioctl(fd, USBDEVFS_BULK, &mut transfer as *mut BulkTransfer as *mut c_void) {
+        Ok(count) => Ok(count as usize),
+        Err(e) if e.raw_os_error() == Some(libc::ETIMEDOUT) => {
+            Err(FlashError::UsbTimeout(format!("Bulk transfer on endpoint 0x{:02x} timed out", endpoint)))
+        }
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EACCES | libc::EPERM)) => Err(FlashError::PermissionLost),
+        Err(e) => Err(FlashError::Usb(format!("Bulk transfer on endpoint 0x{:02x} failed: {}", endpoint, e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ioctl_numbers() {
+        // Values from linux/usbdevice_fs.h
+        assert_eq!(USBDEVFS_CLAIMINTERFACE, 0x8004_550f);
+        assert_eq!(USBDEVFS_RELEASEINTERFACE, 0x8004_5510);
+        let expected_bulk = if cfg!(target_pointer_width = "64") { 0xc018_5502 } else { 0xc010_5502 };
+        assert_eq!(USBDEVFS_BULK, expected_bulk);
+        let expected_control = if cfg!(target_pointer_width = "64") { 0xc018_5500 } else { 0xc010_5500 };
+        assert_eq!(USBDEVFS_CONTROL, expected_control);
+    }
+}